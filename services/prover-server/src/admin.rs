@@ -0,0 +1,187 @@
+//! Admin API, served on its own listener so it can stay off the public network.
+//!
+//! Every request must carry the configured `admin_token` as a bearer token.
+
+use crate::{reload::ConfigReloader, sessions::Sessions, webhook::WebhookDispatcher};
+use axum::{
+    extract::{Path, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, put},
+    Json, Router,
+};
+use std::{fmt, sync::Arc};
+use subtle::ConstantTimeEq;
+use tracing::info;
+use uuid::Uuid;
+
+/// State shared with the admin handlers
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub token: String,
+    pub sessions: Arc<Sessions>,
+    pub webhooks: Option<Arc<WebhookDispatcher>>,
+    /// Set when the configuration was loaded from a file
+    pub reloader: Option<Arc<ConfigReloader>>,
+}
+
+impl fmt::Debug for AdminState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminState")
+            .field("token", &"<redacted>")
+            .field("sessions", &self.sessions)
+            .field("webhooks", &self.webhooks)
+            .field("reloader", &self.reloader)
+            .finish()
+    }
+}
+
+pub(crate) fn router<S: Clone + Send + Sync + 'static>(state: AdminState) -> Router<S> {
+    Router::new()
+        .route("/admin/sessions", get(sessions_handler))
+        .route("/admin/sessions/:id", delete(cancel_session_handler))
+        .route("/admin/maintenance", get(maintenance_handler))
+        .route(
+            "/admin/maintenance/:target",
+            put(start_maintenance_handler).delete(stop_maintenance_handler),
+        )
+        .route("/admin/webhooks/dead-letters", get(dead_letters_handler))
+        .route("/admin/reload", get(reload_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state)
+}
+
+/// Rejects requests without the admin bearer token.
+async fn authenticate(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| bool::from(token.as_bytes().ct_eq(state.token.as_bytes())));
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+/// Lists running sessions, oldest first.
+async fn sessions_handler(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.sessions.list())
+}
+
+/// Cancels a running session. It still reports its result, as failed.
+async fn cancel_session_handler(
+    Path(id): Path<Uuid>,
+    State(state): State<AdminState>,
+) -> StatusCode {
+    if !state.sessions.cancel(id) {
+        return StatusCode::NOT_FOUND;
+    }
+    info!("Cancelled session {id} on admin request");
+    StatusCode::NO_CONTENT
+}
+
+/// Lists the targets that don't accept new sessions.
+async fn maintenance_handler(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.sessions.maintenance())
+}
+
+/// Stops accepting new sessions for a target. Running sessions continue.
+async fn start_maintenance_handler(
+    Path(target): Path<String>,
+    State(state): State<AdminState>,
+) -> StatusCode {
+    state.sessions.set_maintenance(&target, true);
+    info!("Started maintenance of {target}");
+    StatusCode::NO_CONTENT
+}
+
+async fn stop_maintenance_handler(
+    Path(target): Path<String>,
+    State(state): State<AdminState>,
+) -> StatusCode {
+    state.sessions.set_maintenance(&target, false);
+    info!("Stopped maintenance of {target}");
+    StatusCode::NO_CONTENT
+}
+
+/// Lists webhook deliveries that ran out of attempts.
+async fn dead_letters_handler(State(state): State<AdminState>) -> impl IntoResponse {
+    let dead_letters = match &state.webhooks {
+        Some(webhooks) => webhooks.dead_letters().await,
+        None => Vec::new(),
+    };
+    Json(dead_letters)
+}
+
+/// Counts configuration reloads and reports the last error and the changes awaiting a
+/// restart.
+async fn reload_handler(State(state): State<AdminState>) -> Response {
+    match &state.reloader {
+        Some(reloader) => Json(reloader.status()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use tower_service::Service;
+
+    async fn call(
+        router: &mut Router,
+        method: &str,
+        uri: &str,
+        token: &str,
+    ) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn toggles_maintenance_with_admin_token() {
+        let sessions = Arc::new(Sessions::new());
+        let mut router: Router = router(AdminState {
+            token: "secret".into(),
+            sessions: sessions.clone(),
+            webhooks: None,
+            reloader: None,
+        });
+
+        let (status, _) = call(&mut router, "GET", "/admin/sessions", "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = call(&mut router, "GET", "/admin/sessions", "secret").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "[]"));
+
+        let uri = "/admin/maintenance/swissbank.tlsnotary.org";
+        let (status, _) = call(&mut router, "PUT", uri, "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!sessions.in_maintenance("swissbank.tlsnotary.org"));
+        let (status, _) = call(&mut router, "PUT", uri, "secret").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(sessions.in_maintenance("swissbank.tlsnotary.org"));
+        let (_, body) = call(&mut router, "GET", "/admin/maintenance", "secret").await;
+        assert_eq!(body, r#"["swissbank.tlsnotary.org"]"#);
+        call(&mut router, "DELETE", uri, "secret").await;
+        assert!(!sessions.in_maintenance("swissbank.tlsnotary.org"));
+
+        let uri = format!("/admin/sessions/{}", Uuid::new_v4());
+        let (status, _) = call(&mut router, "DELETE", &uri, "secret").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
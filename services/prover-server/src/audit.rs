@@ -0,0 +1,288 @@
+//! Append-only, hash-chained audit log of verification decisions.
+//!
+//! Every record is written as a single JSON line that embeds the hash of the
+//! previous record. Editing, removing or reordering a line breaks the chain,
+//! which [`verify_log`] detects.
+
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+/// `prev_hash` of the first record in a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Outcome of a verification session
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditResult {
+    Verified,
+    Failed,
+    TimedOut,
+}
+
+/// A single verification decision
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub session_id: String,
+    pub wallet: Option<String>,
+    pub target: String,
+    pub policy_version: String,
+    pub result: AuditResult,
+    pub reason: Option<String>,
+    pub transcript_digest: Option<String>,
+    /// Left out when unknown, which keeps the hashes of older records valid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+}
+
+impl AuditEntry {
+    /// Seconds since the Unix epoch, used as the record timestamp.
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// An [`AuditEntry`] as stored on disk, linked to its predecessor
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub prev_hash: String,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn new(seq: u64, prev_hash: String, entry: AuditEntry) -> Result<Self, eyre::ErrReport> {
+        let hash = record_hash(seq, &prev_hash, &entry)?;
+        Ok(Self {
+            seq,
+            prev_hash,
+            entry,
+            hash,
+        })
+    }
+}
+
+/// Result of a successful chain check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainSummary {
+    /// Number of records in the log
+    pub records: u64,
+    /// Hash of the last record, or [`GENESIS_HASH`] for an empty log
+    pub head: String,
+}
+
+/// Appends records to an audit log file, keeping track of the chain head.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug)]
+struct LogState {
+    file: File,
+    next_seq: u64,
+    head: String,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the log at `path`.
+    ///
+    /// The existing chain is verified first; a broken log is never appended to.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, eyre::ErrReport> {
+        let path = path.as_ref().to_path_buf();
+        let summary = match std::fs::read_to_string(&path) {
+            Ok(contents) => verify_chain(&contents)
+                .map_err(|err| eyre!("Refusing to append to {}: {err}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => ChainSummary {
+                records: 0,
+                head: GENESIS_HASH.to_string(),
+            },
+            Err(err) => return Err(eyre!("Failed to read audit log {}: {err}", path.display())),
+        };
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| eyre!("Failed to open audit log {}: {err}", path.display()))?;
+
+        Ok(Self {
+            path,
+            state: Mutex::new(LogState {
+                file: File::from_std(file),
+                next_seq: summary.records,
+                head: summary.head,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `entry` to the chain and flushes it to disk.
+    pub async fn append(&self, entry: AuditEntry) -> Result<AuditRecord, eyre::ErrReport> {
+        let mut state = self.state.lock().await;
+        let record = AuditRecord::new(state.next_seq, state.head.clone(), entry)?;
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        state.file.write_all(&line).await?;
+        state.file.sync_data().await?;
+
+        state.next_seq += 1;
+        state.head = record.hash.clone();
+
+        Ok(record)
+    }
+}
+
+/// Checks the hash chain of the log file at `path`.
+pub fn verify_log(path: impl AsRef<Path>) -> Result<ChainSummary, eyre::ErrReport> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| eyre!("Failed to read audit log {}: {err}", path.display()))?;
+    verify_chain(&contents)
+}
+
+/// Checks the hash chain of newline-delimited audit records.
+pub fn verify_chain(contents: &str) -> Result<ChainSummary, eyre::ErrReport> {
+    let mut expected_seq = 0;
+    let mut head = GENESIS_HASH.to_string();
+
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = index + 1;
+        let record: AuditRecord = serde_json::from_str(line)
+            .map_err(|err| eyre!("Line {line_number}: malformed audit record: {err}"))?;
+
+        if record.seq != expected_seq {
+            return Err(eyre!(
+                "Line {line_number}: expected sequence number {expected_seq}, found {}",
+                record.seq
+            ));
+        }
+        if record.prev_hash != head {
+            return Err(eyre!(
+                "Line {line_number}: record {} does not link to the previous record",
+                record.seq
+            ));
+        }
+        if record_hash(record.seq, &record.prev_hash, &record.entry)? != record.hash {
+            return Err(eyre!(
+                "Line {line_number}: record {} has been modified",
+                record.seq
+            ));
+        }
+
+        expected_seq += 1;
+        head = record.hash;
+    }
+
+    Ok(ChainSummary {
+        records: expected_seq,
+        head,
+    })
+}
+
+/// SHA-256 over the authenticated sent and received bytes of a transcript.
+///
+/// The length of the sent data is hashed first so that moving bytes between
+/// the two directions changes the digest.
+pub fn transcript_digest(sent: &[u8], received: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((sent.len() as u64).to_be_bytes());
+    hasher.update(sent);
+    hasher.update(received);
+    hex::encode(hasher.finalize())
+}
+
+fn record_hash(seq: u64, prev_hash: &str, entry: &AuditEntry) -> Result<String, eyre::ErrReport> {
+    let encoded = serde_json::to_vec(&(seq, prev_hash, entry))?;
+    Ok(hex::encode(Sha256::digest(encoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(session_id: &str, result: AuditResult) -> AuditEntry {
+        AuditEntry {
+            timestamp: 1_700_000_000,
+            session_id: session_id.to_string(),
+            wallet: Some("0x00000000000000000000000000000000000000aa".to_string()),
+            target: "swissbank.tlsnotary.org".to_string(),
+            policy_version: "1".to_string(),
+            result,
+            reason: None,
+            transcript_digest: Some(transcript_digest(b"sent", b"received")),
+            client_ip: Some("203.0.113.7".to_string()),
+        }
+    }
+
+    fn temp_log() -> PathBuf {
+        std::env::temp_dir().join(format!("audit-{}.log", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn appends_and_reopens_chain() {
+        let path = temp_log();
+
+        let log = AuditLog::open(&path).unwrap();
+        log.append(entry("a", AuditResult::Verified)).await.unwrap();
+        let second = log.append(entry("b", AuditResult::Failed)).await.unwrap();
+        drop(log);
+
+        let log = AuditLog::open(&path).unwrap();
+        let third = log.append(entry("c", AuditResult::TimedOut)).await.unwrap();
+        assert_eq!(third.seq, 2);
+        assert_eq!(third.prev_hash, second.hash);
+
+        let summary = verify_log(&path).unwrap();
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.head, third.hash);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn detects_tampering() {
+        let path = temp_log();
+        let log = AuditLog::open(&path).unwrap();
+        for session_id in ["a", "b", "c"] {
+            log.append(entry(session_id, AuditResult::Verified))
+                .await
+                .unwrap();
+        }
+        drop(log);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let edited = contents.replacen("\"verified\"", "\"failed\"", 1);
+        assert!(verify_chain(&edited).is_err());
+
+        let lines: Vec<_> = contents.lines().collect();
+        let removed = [lines[0], lines[2]].join("\n");
+        assert!(verify_chain(&removed).is_err());
+
+        let reordered = [lines[1], lines[0], lines[2]].join("\n");
+        assert!(verify_chain(&reordered).is_err());
+
+        assert!(verify_chain(&contents).is_ok());
+    }
+}
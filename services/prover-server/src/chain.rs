@@ -0,0 +1,626 @@
+//! Optional on-chain sink that submits verified claims to `ZkOracle.submitClaim`.
+//!
+//! Transactions are legacy (EIP-155) transactions signed with the configured agent key
+//! and sent over JSON-RPC, so any node speaking the standard `eth_*` methods works.
+
+use crate::config::OnChainConfig;
+use eyre::eyre;
+use k256::ecdsa::SigningKey;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{debug, info};
+
+/// `submitClaim(address subject, bytes32 claimType, bytes32 claimValue, uint256 expiry, bytes proof)`
+const SUBMIT_CLAIM_SIGNATURE: &str = "submitClaim(address,bytes32,bytes32,uint256,bytes)";
+
+/// A claim accepted by the JSON-RPC node
+#[derive(Clone, Debug)]
+pub struct SubmittedClaim {
+    pub tx_hash: String,
+    pub nonce: u64,
+    pub expiry: u64,
+}
+
+/// Signs and sends `submitClaim` transactions with the agent key.
+#[derive(Debug)]
+pub struct ClaimSubmitter {
+    client: reqwest::Client,
+    rpc_url: String,
+    oracle: [u8; 20],
+    signing_key: SigningKey,
+    sender: [u8; 20],
+    claim_type: [u8; 32],
+    claim_value_group: String,
+    claim_ttl: Duration,
+    configured_chain_id: Option<u64>,
+    chain_id: OnceCell<u64>,
+    /// Next nonce to use on a chain, or `None` if it must be fetched from the node. Shared
+    /// with the submitters reloaded for the same key, see [`ClaimSubmitter::share_nonces`].
+    nonce: Arc<Mutex<Option<(u64, u64)>>>,
+}
+
+impl ClaimSubmitter {
+    pub fn new(config: &OnChainConfig) -> Result<Self, eyre::ErrReport> {
+        let key_bytes = decode_hex(&config.private_key)
+            .map_err(|err| eyre!("Invalid on-chain private key: {err}"))?;
+        let signing_key = SigningKey::from_slice(&key_bytes)
+            .map_err(|err| eyre!("Invalid on-chain private key: {err}"))?;
+        let oracle = parse_address(&config.oracle_address)
+            .map_err(|err| eyre!("Invalid ZkOracle address: {err}"))?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            rpc_url: config.rpc_url.clone(),
+            oracle,
+            sender: address_of(&signing_key),
+            signing_key,
+            claim_type: keccak256(config.claim_type.as_bytes()),
+            claim_value_group: config.claim_value_group.clone(),
+            claim_ttl: Duration::from_secs(config.claim_ttl_secs),
+            configured_chain_id: config.chain_id,
+            chain_id: OnceCell::new(),
+            nonce: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Takes over the nonces of `previous` if it signs with the same key. Sessions still
+    /// running keep submitting with `previous` after a reload, and the two submitters must
+    /// not send transactions with the same nonce.
+    pub fn share_nonces(&mut self, previous: &ClaimSubmitter) {
+        if previous.sender == self.sender {
+            self.nonce = previous.nonce.clone();
+        }
+    }
+
+    /// Address of the agent account that signs the transactions.
+    pub fn address(&self) -> String {
+        format!("0x{}", hex::encode(self.sender))
+    }
+
+    /// Value to submit among the `claims` captured from a verified transcript.
+    pub fn claim_value<'c>(
+        &self,
+        claims: &'c BTreeMap<String, String>,
+    ) -> Result<&'c str, eyre::ErrReport> {
+        claims
+            .get(&self.claim_value_group)
+            .map(String::as_str)
+            .ok_or_else(|| eyre!("No {} claim was verified", self.claim_value_group))
+    }
+
+    /// Submits a claim of `claim_value` for `subject`, using `proof` as the `proof` argument.
+    pub async fn submit(
+        &self,
+        subject: &str,
+        claim_value: &str,
+        proof: &[u8],
+    ) -> Result<SubmittedClaim, eyre::ErrReport> {
+        let subject =
+            parse_address(subject).map_err(|err| eyre!("Invalid subject address: {err}"))?;
+        let claim_value = encode_bytes32_string(claim_value)?;
+        let expiry = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .saturating_add(self.claim_ttl)
+            .as_secs();
+        let data = encode_submit_claim(&subject, &self.claim_type, &claim_value, expiry, proof);
+        let chain_id = self.chain_id().await?;
+
+        // Hold the nonce for the whole submission so concurrent sessions never reuse one.
+        let mut next_nonce = self.nonce.lock().await;
+        let nonce = match *next_nonce {
+            Some((nonce_chain_id, nonce)) if nonce_chain_id == chain_id => nonce,
+            _ => {
+                self.quantity(
+                    "eth_getTransactionCount",
+                    json!([self.address(), "pending"]),
+                )
+                .await? as u64
+            }
+        };
+
+        let result = self.send(chain_id, nonce, &data).await;
+        // A failed send may or may not have consumed the nonce, so ask the node next time.
+        *next_nonce = result.as_ref().ok().map(|_| (chain_id, nonce + 1));
+        let tx_hash = result?;
+
+        info!("Submitted claim in transaction {tx_hash} (nonce {nonce})");
+        Ok(SubmittedClaim {
+            tx_hash,
+            nonce,
+            expiry,
+        })
+    }
+
+    async fn send(
+        &self,
+        chain_id: u64,
+        nonce: u64,
+        data: &[u8],
+    ) -> Result<String, eyre::ErrReport> {
+        let to = format!("0x{}", hex::encode(self.oracle));
+        let gas_price = self.quantity("eth_gasPrice", json!([])).await?;
+        let gas_limit = self
+            .quantity(
+                "eth_estimateGas",
+                json!([{ "from": self.address(), "to": to, "data": format!("0x{}", hex::encode(data)) }]),
+            )
+            .await?;
+        debug!(
+            "Sending submitClaim with nonce {nonce}, gas price {gas_price}, gas limit {gas_limit}"
+        );
+
+        let raw = sign_legacy_transaction(
+            &self.signing_key,
+            &LegacyTransaction {
+                nonce: nonce.into(),
+                gas_price,
+                gas_limit,
+                to: self.oracle,
+                value: 0,
+                data,
+                chain_id,
+            },
+        )?;
+
+        self.call(
+            "eth_sendRawTransaction",
+            json!([format!("0x{}", hex::encode(raw))]),
+        )
+        .await
+    }
+
+    async fn chain_id(&self) -> Result<u64, eyre::ErrReport> {
+        if let Some(chain_id) = self.configured_chain_id {
+            return Ok(chain_id);
+        }
+        self.chain_id
+            .get_or_try_init(|| async { Ok(self.quantity("eth_chainId", json!([])).await? as u64) })
+            .await
+            .copied()
+    }
+
+    async fn quantity(&self, method: &str, params: Value) -> Result<u128, eyre::ErrReport> {
+        let quantity: String = self.call(method, params).await?;
+        parse_quantity(&quantity).map_err(|err| eyre!("{method} returned {quantity}: {err}"))
+    }
+
+    async fn call<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, eyre::ErrReport> {
+        #[derive(Deserialize)]
+        struct RpcError {
+            code: i64,
+            message: String,
+        }
+        #[derive(Deserialize)]
+        struct RpcResponse<T> {
+            result: Option<T>,
+            error: Option<RpcError>,
+        }
+
+        let response: RpcResponse<T> = self
+            .client
+            .post(&self.rpc_url)
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .send()
+            .await
+            .map_err(|err| eyre!("{method} request failed: {err}"))?
+            .error_for_status()
+            .map_err(|err| eyre!("{method} request failed: {err}"))?
+            .json()
+            .await
+            .map_err(|err| eyre!("{method} returned an invalid response: {err}"))?;
+
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(eyre!(
+                "{method} failed with code {}: {}",
+                error.code,
+                error.message
+            )),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(eyre!("{method} returned no result")),
+        }
+    }
+}
+
+/// Parses a `0x`-prefixed, 20-byte hex address.
+pub fn parse_address(address: &str) -> Result<[u8; 20], eyre::ErrReport> {
+    let bytes = decode_hex(address)?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| eyre!("expected 20 bytes, found {}", bytes.len()))
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, eyre::ErrReport> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    Ok(hex::decode(value)?)
+}
+
+fn parse_quantity(quantity: &str) -> Result<u128, eyre::ErrReport> {
+    let digits = quantity
+        .strip_prefix("0x")
+        .ok_or_else(|| eyre!("missing 0x prefix"))?;
+    Ok(u128::from_str_radix(digits, 16)?)
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+fn address_of(signing_key: &SigningKey) -> [u8; 20] {
+    let public_key = signing_key.verifying_key().to_encoded_point(false);
+    let hash = keccak256(&public_key.as_bytes()[1..]);
+    hash[12..].try_into().expect("hash is 32 bytes")
+}
+
+/// Same encoding as ethers' `encodeBytes32String`.
+fn encode_bytes32_string(value: &str) -> Result<[u8; 32], eyre::ErrReport> {
+    if value.len() > 31 {
+        return Err(eyre!("claim value {value:?} is longer than 31 bytes"));
+    }
+    let mut word = [0u8; 32];
+    word[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(word)
+}
+
+/// ABI-encodes a `submitClaim` call, matching `ZkOracle.sol`.
+fn encode_submit_claim(
+    subject: &[u8; 20],
+    claim_type: &[u8; 32],
+    claim_value: &[u8; 32],
+    expiry: u64,
+    proof: &[u8],
+) -> Vec<u8> {
+    let mut data = keccak256(SUBMIT_CLAIM_SIGNATURE.as_bytes())[..4].to_vec();
+
+    data.extend_from_slice(&[0u8; 12]);
+    data.extend_from_slice(subject);
+    data.extend_from_slice(claim_type);
+    data.extend_from_slice(claim_value);
+    data.extend_from_slice(&abi_uint(expiry.into()));
+    // Offset of the dynamic `proof` argument, right after the five head words.
+    data.extend_from_slice(&abi_uint(5 * 32));
+
+    data.extend_from_slice(&abi_uint(proof.len() as u128));
+    data.extend_from_slice(proof);
+    data.resize(data.len() + (32 - proof.len() % 32) % 32, 0);
+
+    data
+}
+
+fn abi_uint(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+struct LegacyTransaction<'a> {
+    nonce: u128,
+    gas_price: u128,
+    gas_limit: u128,
+    to: [u8; 20],
+    value: u128,
+    data: &'a [u8],
+    chain_id: u64,
+}
+
+/// Signs `tx` with EIP-155 replay protection and returns the raw RLP-encoded transaction.
+fn sign_legacy_transaction(
+    signing_key: &SigningKey,
+    tx: &LegacyTransaction<'_>,
+) -> Result<Vec<u8>, eyre::ErrReport> {
+    let mut fields = vec![
+        rlp_uint(tx.nonce),
+        rlp_uint(tx.gas_price),
+        rlp_uint(tx.gas_limit),
+        rlp_bytes(&tx.to),
+        rlp_uint(tx.value),
+        rlp_bytes(tx.data),
+    ];
+
+    let mut unsigned = fields.clone();
+    unsigned.extend([rlp_uint(tx.chain_id.into()), rlp_uint(0), rlp_uint(0)]);
+    let sighash = keccak256(&rlp_list(&unsigned));
+
+    let (signature, recovery_id) = signing_key
+        .sign_prehash_recoverable(&sighash)
+        .map_err(|err| eyre!("Failed to sign transaction: {err}"))?;
+    let v = u128::from(recovery_id.to_byte()) + u128::from(tx.chain_id) * 2 + 35;
+    let (r, s) = signature.split_bytes();
+
+    fields.extend([
+        rlp_uint(v),
+        rlp_bytes(trim_leading_zeros(&r)),
+        rlp_bytes(trim_leading_zeros(&s)),
+    ]);
+    Ok(rlp_list(&fields))
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn rlp_uint(value: u128) -> Vec<u8> {
+    rlp_bytes(trim_leading_zeros(&value.to_be_bytes()))
+}
+
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    if let [byte] = bytes {
+        if *byte < 0x80 {
+            return vec![*byte];
+        }
+    }
+    let mut encoded = rlp_length_prefix(0x80, bytes.len());
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+    let mut encoded = rlp_length_prefix(0xc0, payload.len());
+    encoded.extend(payload);
+    encoded
+}
+
+fn rlp_length_prefix(offset: u8, len: usize) -> Vec<u8> {
+    if len <= 55 {
+        vec![offset + len as u8]
+    } else {
+        let len_bytes = trim_leading_zeros(&len.to_be_bytes()).to_vec();
+        let mut prefix = vec![offset + 55 + len_bytes.len() as u8];
+        prefix.extend(len_bytes);
+        prefix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::post, Json, Router};
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    #[test]
+    fn signs_eip155_example_transaction() {
+        // Example transaction from EIP-155.
+        let signing_key = SigningKey::from_slice(&[0x46; 32]).unwrap();
+        let raw = sign_legacy_transaction(
+            &signing_key,
+            &LegacyTransaction {
+                nonce: 9,
+                gas_price: 20_000_000_000,
+                gas_limit: 21_000,
+                to: [0x35; 20],
+                value: 1_000_000_000_000_000_000,
+                data: &[],
+                chain_id: 1,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            hex::encode(raw),
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+    }
+
+    #[test]
+    fn encodes_submit_claim_call() {
+        let data = encode_submit_claim(
+            &[0xaa; 20],
+            &keccak256(b"ELIGIBLE"),
+            &encode_bytes32_string("true").unwrap(),
+            0x1234,
+            &[0xbb; 33],
+        );
+
+        assert_eq!(hex::encode(&data[..4]), "dc9b0919");
+        assert_eq!(data.len(), 4 + 5 * 32 + 32 + 64);
+        assert_eq!(&data[4 + 12..4 + 32], &[0xaa; 20]);
+        assert_eq!(&data[4 + 64..4 + 68], b"true");
+        assert_eq!(data[4 + 4 * 32 - 1], 0x34);
+        assert_eq!(data[4 + 5 * 32 - 1], 160);
+        assert_eq!(data[4 + 6 * 32 - 1], 33);
+        assert_eq!(&data[4 + 6 * 32..4 + 6 * 32 + 33], &[0xbb; 33]);
+        assert!(data[4 + 6 * 32 + 33..].iter().all(|&b| b == 0));
+    }
+
+    /// Minimal JSON-RPC node that records raw transactions.
+    #[derive(Clone, Default)]
+    struct MockNode {
+        raw_transactions: Arc<StdMutex<Vec<String>>>,
+        nonce_queries: Arc<StdMutex<usize>>,
+        reject_next: Arc<StdMutex<bool>>,
+    }
+
+    async fn rpc(State(node): State<MockNode>, Json(request): Json<Value>) -> Json<Value> {
+        let id = request["id"].clone();
+        let result = match request["method"].as_str().unwrap() {
+            "eth_chainId" => json!("0x138b"),
+            "eth_gasPrice" => json!("0x3b9aca00"),
+            "eth_estimateGas" => json!("0x30d40"),
+            "eth_getTransactionCount" => {
+                *node.nonce_queries.lock().unwrap() += 1;
+                json!("0x7")
+            }
+            "eth_sendRawTransaction" => {
+                if std::mem::take(&mut *node.reject_next.lock().unwrap()) {
+                    return Json(
+                        json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32000, "message": "nonce too low" } }),
+                    );
+                }
+                let raw = request["params"][0].as_str().unwrap().to_string();
+                node.raw_transactions.lock().unwrap().push(raw.clone());
+                json!(format!(
+                    "0x{}",
+                    hex::encode(keccak256(&decode_hex(&raw).unwrap()))
+                ))
+            }
+            method => panic!("unexpected method {method}"),
+        };
+        Json(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+
+    /// Serves `node`, returning its URL.
+    async fn spawn_node(node: MockNode) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/", post(rpc)).with_state(node);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        rpc_url
+    }
+
+    #[tokio::test]
+    async fn submits_claims_with_sequential_nonces() {
+        let node = MockNode::default();
+        let rpc_url = spawn_node(node.clone()).await;
+
+        let submitter = ClaimSubmitter::new(&OnChainConfig {
+            rpc_url,
+            oracle_address: format!("0x{}", "11".repeat(20)),
+            private_key: "46".repeat(32),
+            ..Default::default()
+        })
+        .unwrap();
+        let subject = format!("0x{}", "aa".repeat(20));
+
+        let first = submitter.submit(&subject, "true", &[1; 32]).await.unwrap();
+        let second = submitter.submit(&subject, "true", &[2; 32]).await.unwrap();
+        assert_eq!((first.nonce, second.nonce), (7, 8));
+        assert_eq!(*node.nonce_queries.lock().unwrap(), 1);
+
+        // A rejected transaction makes the submitter resync its nonce from the node.
+        *node.reject_next.lock().unwrap() = true;
+        assert!(submitter.submit(&subject, "true", &[3; 32]).await.is_err());
+        let fourth = submitter.submit(&subject, "true", &[4; 32]).await.unwrap();
+        assert_eq!(fourth.nonce, 7);
+        assert_eq!(*node.nonce_queries.lock().unwrap(), 2);
+
+        let raw_transactions = node.raw_transactions.lock().unwrap();
+        assert_eq!(raw_transactions.len(), 3);
+        let calldata = hex::encode(encode_submit_claim(
+            &parse_address(&subject).unwrap(),
+            &keccak256(b"ELIGIBLE"),
+            &encode_bytes32_string("true").unwrap(),
+            first.expiry,
+            &[1; 32],
+        ));
+        assert!(raw_transactions[0].contains(&calldata));
+        assert_eq!(
+            first.tx_hash,
+            format!(
+                "0x{}",
+                hex::encode(keccak256(&decode_hex(&raw_transactions[0]).unwrap()))
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn shares_nonces_with_reloaded_submitters() {
+        let node = MockNode::default();
+        let config = OnChainConfig {
+            rpc_url: spawn_node(node.clone()).await,
+            oracle_address: format!("0x{}", "11".repeat(20)),
+            private_key: "46".repeat(32),
+            ..Default::default()
+        };
+        let subject = format!("0x{}", "aa".repeat(20));
+
+        let previous = ClaimSubmitter::new(&config).unwrap();
+        assert_eq!(
+            previous
+                .submit(&subject, "true", &[1; 32])
+                .await
+                .unwrap()
+                .nonce,
+            7
+        );
+
+        let mut reloaded = ClaimSubmitter::new(&OnChainConfig {
+            claim_type: "TIER".into(),
+            ..config.clone()
+        })
+        .unwrap();
+        reloaded.share_nonces(&previous);
+        assert_eq!(
+            reloaded
+                .submit(&subject, "true", &[2; 32])
+                .await
+                .unwrap()
+                .nonce,
+            8
+        );
+        assert_eq!(
+            previous
+                .submit(&subject, "true", &[3; 32])
+                .await
+                .unwrap()
+                .nonce,
+            9
+        );
+        assert_eq!(*node.nonce_queries.lock().unwrap(), 1);
+
+        // Another key has nonces of its own
+        let mut other_key = ClaimSubmitter::new(&OnChainConfig {
+            private_key: "47".repeat(32),
+            ..config
+        })
+        .unwrap();
+        other_key.share_nonces(&previous);
+        assert_eq!(
+            other_key
+                .submit(&subject, "true", &[4; 32])
+                .await
+                .unwrap()
+                .nonce,
+            7
+        );
+        assert_eq!(*node.nonce_queries.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn takes_the_claim_value_from_verified_claims() {
+        let submitter = ClaimSubmitter::new(&OnChainConfig {
+            oracle_address: format!("0x{}", "11".repeat(20)),
+            private_key: "46".repeat(32),
+            claim_value_group: "tier".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let claims = BTreeMap::from([("tier".to_string(), "gold".to_string())]);
+        assert_eq!(submitter.claim_value(&claims).unwrap(), "gold");
+        assert!(submitter.claim_value(&BTreeMap::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_claim_values_longer_than_a_word() {
+        let submitter = ClaimSubmitter::new(&OnChainConfig {
+            rpc_url: spawn_node(MockNode::default()).await,
+            oracle_address: format!("0x{}", "11".repeat(20)),
+            private_key: "46".repeat(32),
+            ..Default::default()
+        })
+        .unwrap();
+        let subject = format!("0x{}", "aa".repeat(20));
+        assert!(submitter
+            .submit(&subject, &"x".repeat(32), &[1; 32])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert!(parse_address("0x1234").is_err());
+        assert!(parse_address(&format!("0x{}", "zz".repeat(20))).is_err());
+        assert!(parse_address(&format!("0x{}", "ab".repeat(20))).is_ok());
+    }
+}
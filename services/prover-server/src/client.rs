@@ -0,0 +1,148 @@
+//! Headless prover client.
+//!
+//! Connects to the `/verify` endpoint of a server over WebSocket and proves data from the
+//! configured target to it, the same way the browser extension does through `/prove`.
+
+use crate::{
+    config::{ProtocolTuning, RedactionPolicy, SessionTimeouts},
+    prover::prover,
+    timeouts::{expired, within, Deadline},
+    tls::TargetRoots,
+};
+use eyre::eyre;
+use http::Uri;
+use serde::Serialize;
+use std::time::Instant;
+use tracing::info;
+use ws_stream_tungstenite::WsStream;
+
+/// Outcome of a proving session run by the client
+#[derive(Clone, Debug, Serialize)]
+pub struct ProveOutcome {
+    pub verifier_url: String,
+    pub target: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub error_code: Option<String>,
+    pub elapsed_ms: u128,
+}
+
+/// Proves data from `server_uri` to the verifier at `verifier_url` (`ws://` or `wss://`).
+///
+/// When set, `wallet` is passed to the verifier as the `wallet` query parameter.
+pub async fn prove_to_verifier(
+    verifier_url: &str,
+    wallet: Option<&str>,
+    server_uri: &Uri,
+    roots: &TargetRoots,
+    policy: &RedactionPolicy,
+    tuning: &ProtocolTuning,
+    timeouts: &SessionTimeouts,
+) -> ProveOutcome {
+    let started = Instant::now();
+    let result = run(
+        verifier_url,
+        wallet,
+        server_uri,
+        roots,
+        policy,
+        tuning,
+        timeouts,
+    )
+    .await;
+
+    ProveOutcome {
+        verifier_url: verifier_url.to_string(),
+        target: server_uri.host().unwrap_or_default().to_string(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+        error_code: result
+            .as_ref()
+            .err()
+            .and_then(expired)
+            .map(|deadline| deadline.code().to_string()),
+        elapsed_ms: started.elapsed().as_millis(),
+    }
+}
+
+async fn run(
+    verifier_url: &str,
+    wallet: Option<&str>,
+    server_uri: &Uri,
+    roots: &TargetRoots,
+    policy: &RedactionPolicy,
+    tuning: &ProtocolTuning,
+    timeouts: &SessionTimeouts,
+) -> Result<(), eyre::ErrReport> {
+    let url = session_url(verifier_url, wallet)?;
+
+    info!("Connecting to verifier at {verifier_url}");
+    let (ws_stream, _) = within(
+        Deadline::Setup,
+        timeouts.get(Deadline::Setup),
+        async_tungstenite::tokio::connect_async(url.to_string()),
+    )
+    .await?
+    .map_err(|err| eyre!("Failed to connect to verifier {verifier_url}: {err}"))?;
+
+    prover(
+        WsStream::new(ws_stream),
+        server_uri,
+        roots,
+        policy,
+        tuning,
+        timeouts,
+    )
+    .await
+}
+
+/// Appends the session query parameters to the verifier URL.
+fn session_url(verifier_url: &str, wallet: Option<&str>) -> Result<Uri, eyre::ErrReport> {
+    let uri: Uri = verifier_url
+        .parse()
+        .map_err(|err| eyre!("Invalid verifier URL {verifier_url}: {err}"))?;
+    if !matches!(uri.scheme_str(), Some("ws" | "wss")) {
+        return Err(eyre!("Verifier URL must use ws:// or wss://"));
+    }
+    let Some(wallet) = wallet else {
+        return Ok(uri);
+    };
+    if !wallet.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(eyre!("Invalid wallet address {wallet}"));
+    }
+
+    let separator = if uri.query().is_some() { '&' } else { '?' };
+    format!("{verifier_url}{separator}wallet={wallet}")
+        .parse()
+        .map_err(|err| eyre!("Invalid verifier URL {verifier_url}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_session_url() {
+        let wallet = "0x00000000000000000000000000000000000000aa";
+        assert_eq!(
+            session_url("ws://localhost:9816/verify", Some(wallet))
+                .unwrap()
+                .to_string(),
+            format!("ws://localhost:9816/verify?wallet={wallet}")
+        );
+        assert_eq!(
+            session_url("wss://example.com/verify?x=1", Some(wallet))
+                .unwrap()
+                .to_string(),
+            format!("wss://example.com/verify?x=1&wallet={wallet}")
+        );
+        assert_eq!(
+            session_url("ws://localhost:9816/verify", None)
+                .unwrap()
+                .to_string(),
+            "ws://localhost:9816/verify"
+        );
+        assert!(session_url("https://example.com/verify", None).is_err());
+        assert!(session_url("ws://localhost/verify", Some("0xaa&x=1")).is_err());
+    }
+}
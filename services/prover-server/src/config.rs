@@ -1,5 +1,15 @@
+use eyre::eyre;
 use http::Uri;
-/// Configuration constants for the TLSNotary server
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+// Configuration constants for the TLSNotary server
 
 /// Maximum number of bytes that can be sent from prover to server
 pub const MAX_SENT_DATA: usize = 148;
@@ -7,13 +17,70 @@ pub const MAX_SENT_DATA: usize = 148;
 /// Maximum number of bytes that can be received by prover from server
 pub const MAX_RECV_DATA: usize = 460;
 
+/// Shown in place of secrets when a configuration is debug-printed
+const REDACTED: &str = "<redacted>";
+
 /// Default server configuration
+///
+/// Every field can be overridden from a TOML file, see [`Config::load`].
+#[derive(Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    pub ws_host: String,           // Address for WebSocket server
-    pub ws_port: u16,              // Port for WebSocket server
-    pub server_uri: Uri,           // URI of the server from which data is proven with TLSNotary
-    pub wstcp_proxy_port: u16,     // Port for the wstcp proxy server
-    pub session_timeout_secs: u64, // Maximum duration for a WebSocket session in seconds
+    pub ws_host: String,            // Address for WebSocket server, IPv4 or IPv6
+    pub ws_port: u16,               // Port for WebSocket server
+    pub listen: Vec<ListenAddress>, // Replaces `ws_host` and `ws_port` when set, e.g. `["0.0.0.0:9816", "[::]:9816", "unix:/run/prover.sock"]`
+    #[serde(deserialize_with = "deserialize_uri")]
+    pub server_uri: Uri, // URI of the server from which data is proven with TLSNotary
+    #[serde(deserialize_with = "deserialize_nets")]
+    pub trusted_proxies: Vec<IpNet>, // Peers whose `Forwarded` and `X-Forwarded-For` headers are used, e.g. `["127.0.0.1/32"]`
+    pub proxy_protocol: bool, // Expect a PROXY protocol v1 or v2 header on every connection, e.g. from HAProxy
+    pub rate_limit: Option<RateLimitConfig>, // Limit on the sessions each client IP opens
+    pub wstcp_proxy_host: String, // Address for the wstcp proxy server, IPv4 or IPv6
+    pub wstcp_proxy_port: u16, // Port for the wstcp proxy server
+    pub timeouts: SessionTimeouts, // Deadlines of the phases of a session
+    pub policy_version: String, // Version of the verification rules, recorded in audit records
+    pub audit_log_path: Option<PathBuf>, // Append-only audit log of verification decisions
+    pub on_chain: Option<OnChainConfig>, // Submit verified claims to ZkOracle when set
+    pub webhooks: WebhooksConfig, // Sinks notified when a session finishes
+    pub notary: Option<NotaryConfig>, // Enables the /notarize endpoint when set
+    pub admin_token: Option<String>, // Bearer token for the admin endpoints, disabled when unset
+    pub admin_listen: Option<ListenAddress>, // Serves the admin endpoints when set, e.g. `127.0.0.1:9817`; requires `admin_token`
+    pub target_tls: TargetTlsConfig,         // Trust store for the certificate of the target server
+    pub listener_tls: Option<ListenerTlsConfig>, // Serve wss:// directly instead of behind a reverse proxy
+    pub redaction: RedactionPolicy, // Parts of the transcript the prover reveals to the verifier
+    pub protocol: ProtocolTuning,   // MPC-TLS settings of the prover
+    pub protocol_targets: BTreeMap<String, TuningOverrides>, // Per target host, e.g. `[protocol_targets."swissbank.tlsnotary.org"]`
+    pub verifier_limits: ProtocolLimits, // Largest protocol settings the verifier accepts from provers
+    pub telemetry: Option<TelemetryConfig>, // Export session phase spans over OTLP when set
+    pub config_reload_interval_secs: u64, // How often the configuration file is checked for changes, also reloaded on SIGHUP
+}
+
+/// Settings for submitting verified claims to `ZkOracle.submitClaim`
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct OnChainConfig {
+    pub rpc_url: String,           // JSON-RPC endpoint of the chain
+    pub chain_id: Option<u64>,     // Queried with eth_chainId when not set
+    pub oracle_address: String,    // Address of the deployed ZkOracle contract
+    pub private_key: String,       // Hex-encoded key of an account holding AGENT_ROLE
+    pub claim_type: String,        // Hashed with keccak256, like the relayer does
+    pub claim_value_group: String, // Named group of `redaction.reveal_regex` whose verified value is submitted, at most 31 bytes
+    pub claim_ttl_secs: u64,       // Lifetime of the submitted claim
+}
+
+impl Default for OnChainConfig {
+    fn default() -> Self {
+        Self {
+            // Mantle Sepolia
+            rpc_url: "https://rpc.sepolia.mantle.xyz".into(),
+            chain_id: None,
+            oracle_address: String::new(),
+            private_key: String::new(),
+            claim_type: "ELIGIBLE".into(),
+            claim_value_group: "claim_value".into(),
+            claim_ttl_secs: 30 * 24 * 60 * 60,
+        }
+    }
 }
 
 impl Default for Config {
@@ -21,16 +88,472 @@ impl Default for Config {
         Self {
             ws_host: "0.0.0.0".into(),
             ws_port: 9816,
+            listen: Vec::new(),
             // SwissBank demo endpoint
-            server_uri: format!("https://swissbank.tlsnotary.org/balances")
+            server_uri: "https://swissbank.tlsnotary.org/balances"
                 .parse::<Uri>()
                 .unwrap(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            rate_limit: None,
+            wstcp_proxy_host: "127.0.0.1".into(),
             wstcp_proxy_port: 55688,
-            session_timeout_secs: 120,
+            timeouts: SessionTimeouts::default(),
+            policy_version: "1".into(),
+            audit_log_path: None,
+            on_chain: None,
+            webhooks: WebhooksConfig::default(),
+            notary: None,
+            admin_token: None,
+            admin_listen: None,
+            target_tls: TargetTlsConfig::default(),
+            listener_tls: None,
+            redaction: RedactionPolicy::default(),
+            protocol: ProtocolTuning::default(),
+            protocol_targets: BTreeMap::new(),
+            verifier_limits: ProtocolLimits::default(),
+            telemetry: None,
+            config_reload_interval_secs: 10,
+        }
+    }
+}
+
+/// Address the server accepts connections on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// `0.0.0.0:9816` or `[::]:9816`
+    Tcp(SocketAddr),
+    /// `unix:/run/prover.sock`, for sidecar deployments behind a reverse proxy
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = eyre::ErrReport;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        match address.strip_prefix("unix:") {
+            Some("") => Err(eyre!("Missing Unix socket path in {address}")),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => address
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|err| eyre!("Invalid listen address {address}: {err}")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => address.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
         }
     }
 }
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Fixed-window limit on the sessions a client IP can open
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub max_sessions: u32, // Sessions per client IP and window
+    pub window_secs: u64,  // Length of the window
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: 10,
+            window_secs: 60,
+        }
+    }
+}
+
+/// Parts of the transcript the prover reveals; everything else stays hidden from the verifier
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RedactionPolicy {
+    pub redact_request_headers: Vec<String>, // Request headers whose values are hidden, e.g. credentials
+    pub reveal_json: Vec<String>, // Response body fields, like `accounts[0].balance`, `accounts.*` or `items[*].id`
+    pub reveal_regex: Vec<RegexRule>, // Regex matches in the response, for HTML or plain text sources
+    pub reveal_status_line: bool,     // The verifier then requires status 200
+    pub reveal_response_headers: Vec<String>, // Response headers revealed in full, e.g. `date`
+    pub expected_content_type: Option<String>, // Checked by the verifier; reveals the status line and content-type
+    pub required_content: Vec<String>, // Text the verifier requires in the revealed response
+}
+
+/// Reveals every match of a regex in the response
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegexRule {
+    pub pattern: String, // Named capture groups become claim fields on the verifier side
+    #[serde(default)]
+    pub scope: RegexScope,
+}
+
+/// Part of the response a [`RegexRule`] is matched against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegexScope {
+    #[default]
+    Body,
+    /// Each header line on its own, like `content-type: text/html`
+    Headers,
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        Self {
+            redact_request_headers: vec!["authorization".into()],
+            // SwissBank format
+            reveal_json: vec![
+                "organization".into(),
+                "bank".into(),
+                "accounts.USD".into(),
+                "accounts.EUR".into(),
+                "accounts.CHF".into(),
+            ],
+            reveal_regex: Vec::new(),
+            reveal_status_line: false,
+            reveal_response_headers: Vec::new(),
+            expected_content_type: None,
+            // SwissBank format
+            required_content: vec!["Ethereum Foundation".into()],
+        }
+    }
+}
+
+/// MPC-TLS protocol settings of the prover
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ProtocolTuning {
+    pub network: NetworkMode,   // Trade-off of the MPC, see [`NetworkMode`]
+    pub defer_decryption: bool, // Decrypt the response only after the connection closed, which is faster
+    pub max_sent_data: usize,   // Bytes the prover can send to the target
+    pub max_recv_data: usize,   // Bytes the prover can receive from the target
+    pub max_recv_data_online: Option<usize>, // Received bytes decrypted while the connection is open, tlsn default when unset
+    pub max_sent_records: Option<usize>, // TLS records the prover can send, tlsn default when unset
+    pub max_recv_records_online: Option<usize>, // TLS records decrypted while the connection is open
+}
+
+impl Default for ProtocolTuning {
+    fn default() -> Self {
+        Self {
+            network: NetworkMode::default(),
+            defer_decryption: true,
+            max_sent_data: MAX_SENT_DATA,
+            max_recv_data: MAX_RECV_DATA,
+            max_recv_data_online: None,
+            max_sent_records: None,
+            max_recv_records_online: None,
+        }
+    }
+}
+
+/// Which resource the MPC protocol is optimized for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkMode {
+    /// Less data sent but more round trips, for low-latency connections or limited
+    /// bandwidth. The tlsn default.
+    #[default]
+    Latency,
+    /// Fewer round trips but more data sent, for high-latency connections like mobile ones
+    /// when bandwidth is plentiful
+    Bandwidth,
+}
+
+/// Replaces the matching [`ProtocolTuning`] settings, for a target or a session
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct TuningOverrides {
+    pub network: Option<NetworkMode>,
+    pub defer_decryption: Option<bool>,
+    pub max_sent_data: Option<usize>,
+    pub max_recv_data: Option<usize>,
+    pub max_recv_data_online: Option<usize>,
+}
+
+impl ProtocolTuning {
+    /// Applies `overrides`, checking the result is consistent.
+    pub fn with(&self, overrides: &TuningOverrides) -> Result<Self, eyre::ErrReport> {
+        let tuning = Self {
+            network: overrides.network.unwrap_or(self.network),
+            defer_decryption: overrides.defer_decryption.unwrap_or(self.defer_decryption),
+            max_sent_data: overrides.max_sent_data.unwrap_or(self.max_sent_data),
+            max_recv_data: overrides.max_recv_data.unwrap_or(self.max_recv_data),
+            max_recv_data_online: overrides.max_recv_data_online.or(self.max_recv_data_online),
+            ..self.clone()
+        };
+        if let Some(online) = tuning.max_recv_data_online {
+            if online > tuning.max_recv_data {
+                return Err(eyre!(
+                    "max_recv_data_online ({online}) exceeds max_recv_data ({})",
+                    tuning.max_recv_data
+                ));
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+/// Range of prover settings accepted by the verifier
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ProtocolLimits {
+    pub max_sent_data: usize,            // Largest `max_sent_data` of a prover
+    pub max_recv_data: usize,            // Largest `max_recv_data` of a prover
+    pub max_sent_records: Option<usize>, // tlsn default when unset
+    pub max_recv_records_online: Option<usize>, // tlsn default when unset
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_sent_data: MAX_SENT_DATA,
+            max_recv_data: MAX_RECV_DATA,
+            max_sent_records: None,
+            max_recv_records_online: None,
+        }
+    }
+}
+
+/// Deadlines of the phases of a session, in seconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SessionTimeouts {
+    pub setup_secs: u64,    // MPC setup between prover and verifier
+    pub connect_secs: u64,  // TCP connection and MPC-TLS handshake with the target server
+    pub response_secs: u64, // HTTP request and response over MPC-TLS
+    pub finalize_secs: u64, // Proving or verifying the revealed transcript, and closing
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> Self {
+        Self {
+            setup_secs: 30,
+            connect_secs: 15,
+            response_secs: 45,
+            finalize_secs: 30,
+        }
+    }
+}
+
+/// Export of traces and metrics to an OpenTelemetry collector
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: String, // OTLP/HTTP traces endpoint of the collector
+    pub otlp_metrics_endpoint: String, // OTLP/HTTP metrics endpoint of the collector
+    pub service_name: String,  // Reported as `service.name`
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: "http://localhost:4318/v1/traces".into(),
+            otlp_metrics_endpoint: "http://localhost:4318/v1/metrics".into(),
+            service_name: "prover-server".into(),
+        }
+    }
+}
+
+/// Trust store for the certificate of the target server, used by both prover and verifier
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TargetTlsConfig {
+    pub root_certs: Vec<PathBuf>, // PEM files with extra root certificates, e.g. certs/fullchain.pem
+    pub custom_roots_only: bool, // Trust only `root_certs` instead of adding them to the Mozilla roots
+}
+
+/// TLS termination for the WebSocket listener
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ListenerTlsConfig {
+    pub cert_path: PathBuf,         // PEM certificate chain
+    pub key_path: PathBuf,          // PEM private key
+    pub reload_interval_secs: u64,  // How often the files are checked for a renewed certificate
+    pub redirect_port: Option<u16>, // Plain HTTP listener redirecting to HTTPS when set
+}
+
+impl Default for ListenerTlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: PathBuf::new(),
+            key_path: PathBuf::new(),
+            reload_interval_secs: 60,
+            redirect_port: None,
+        }
+    }
+}
+
+/// Settings for notarization mode
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct NotaryConfig {
+    pub private_key: String, // Hex-encoded secp256k1 key that signs attestations
+    pub request_timeout_secs: u64, // How long a notarized session waits for its attestation request
+}
+
+impl Default for NotaryConfig {
+    fn default() -> Self {
+        Self {
+            private_key: String::new(),
+            request_timeout_secs: 300,
+        }
+    }
+}
+
+/// Settings for webhook delivery of session results
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub sinks: Vec<WebhookSink>,     // Every event is delivered to each sink
+    pub state_path: Option<PathBuf>, // Pending deliveries and dead letters, kept across restarts
+    pub max_attempts: u32,           // Attempts before a delivery becomes a dead letter
+    pub retry_base_ms: u64,          // Delay after the first failure, doubled after each one
+    pub retry_max_ms: u64,           // Upper bound on the delay between attempts
+    pub max_dead_letters: usize, // Dead letters kept in memory and in the state file, oldest dropped first
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            sinks: Vec::new(),
+            state_path: None,
+            max_attempts: 10,
+            retry_base_ms: 5_000,
+            retry_max_ms: 60 * 60 * 1000,
+            max_dead_letters: 1000,
+        }
+    }
+}
+
+/// A webhook endpoint
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct WebhookSink {
+    pub url: String,
+    pub secret: String, // Key for the HMAC-SHA256 signature header
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("ws_host", &self.ws_host)
+            .field("ws_port", &self.ws_port)
+            .field("listen", &self.listen)
+            .field("server_uri", &self.server_uri)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("rate_limit", &self.rate_limit)
+            .field("wstcp_proxy_host", &self.wstcp_proxy_host)
+            .field("wstcp_proxy_port", &self.wstcp_proxy_port)
+            .field("timeouts", &self.timeouts)
+            .field("policy_version", &self.policy_version)
+            .field("audit_log_path", &self.audit_log_path)
+            .field("on_chain", &self.on_chain)
+            .field("webhooks", &self.webhooks)
+            .field("notary", &self.notary)
+            .field("admin_token", &self.admin_token.as_ref().map(|_| REDACTED))
+            .field("admin_listen", &self.admin_listen)
+            .field("target_tls", &self.target_tls)
+            .field("listener_tls", &self.listener_tls)
+            .field("redaction", &self.redaction)
+            .field("protocol", &self.protocol)
+            .field("protocol_targets", &self.protocol_targets)
+            .field("verifier_limits", &self.verifier_limits)
+            .field("telemetry", &self.telemetry)
+            .field(
+                "config_reload_interval_secs",
+                &self.config_reload_interval_secs,
+            )
+            .finish()
+    }
+}
+
+impl fmt::Debug for OnChainConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnChainConfig")
+            .field("rpc_url", &self.rpc_url)
+            .field("chain_id", &self.chain_id)
+            .field("oracle_address", &self.oracle_address)
+            .field("private_key", &REDACTED)
+            .field("claim_type", &self.claim_type)
+            .field("claim_value_group", &self.claim_value_group)
+            .field("claim_ttl_secs", &self.claim_ttl_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NotaryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotaryConfig")
+            .field("private_key", &REDACTED)
+            .field("request_timeout_secs", &self.request_timeout_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for WebhookSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookSink")
+            .field("url", &self.url)
+            .field("secret", &REDACTED)
+            .finish()
+    }
+}
+
 impl Config {
+    /// Loads the configuration from a TOML file. Missing keys keep their default value.
+    pub fn load(path: &Path) -> Result<Self, eyre::ErrReport> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| eyre!("Failed to read config file {}: {err}", path.display()))?;
+        let config: Self = toml::from_str(&contents)
+            .map_err(|err| eyre!("Failed to parse config file {}: {err}", path.display()))?;
+        // Deliveries are restored to their sink by URL after a restart
+        let sinks = &config.webhooks.sinks;
+        for (index, sink) in sinks.iter().enumerate() {
+            if sinks[..index].iter().any(|other| other.url == sink.url) {
+                return Err(eyre!("Webhook sink {} is configured twice", sink.url));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Addresses of the WebSocket server: `listen`, or `ws_host` and `ws_port` when empty.
+    pub fn listen_addresses(&self) -> Result<Vec<ListenAddress>, eyre::ErrReport> {
+        if !self.listen.is_empty() {
+            return Ok(self.listen.clone());
+        }
+        let host: IpAddr = self.ws_host.parse().map_err(|err| {
+            eyre!(
+                "Failed to parse websocket host address {}: {err}",
+                self.ws_host
+            )
+        })?;
+        Ok(vec![ListenAddress::Tcp(SocketAddr::new(
+            host,
+            self.ws_port,
+        ))])
+    }
+
+    /// Prover settings for the target `host`, with its overrides applied.
+    pub fn protocol_for(&self, host: &str) -> Result<ProtocolTuning, eyre::ErrReport> {
+        match self.protocol_targets.get(host) {
+            Some(overrides) => self
+                .protocol
+                .with(overrides)
+                .map_err(|err| eyre!("Invalid protocol settings for {host}: {err}")),
+            None => self.protocol.with(&TuningOverrides::default()),
+        }
+    }
+
     pub fn server_domain(&self) -> String {
         self.server_uri
             .host()
@@ -38,3 +561,146 @@ impl Config {
             .to_string()
     }
 }
+
+fn deserialize_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        // A plain address trusts only that address
+        .map(|net| {
+            net.parse()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| serde::de::Error::custom(format!("Invalid trusted proxy {net}")))
+        })
+        .collect()
+}
+
+fn deserialize_uri<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uri, D::Error> {
+    let uri = String::deserialize(deserializer)?;
+    uri.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secrets_from_debug_output() {
+        let config: Config = toml::from_str(
+            r#"
+            admin_token = "admin-secret"
+            [on_chain]
+            private_key = "chain-secret"
+            [notary]
+            private_key = "notary-secret"
+            [[webhooks.sinks]]
+            url = "https://hooks.example.com"
+            secret = "sink-secret"
+            "#,
+        )
+        .unwrap();
+        let printed = format!("{config:?}");
+        assert!(printed.contains("https://hooks.example.com"));
+        for secret in [
+            "admin-secret",
+            "chain-secret",
+            "notary-secret",
+            "sink-secret",
+        ] {
+            assert!(!printed.contains(secret), "{secret} leaked");
+        }
+    }
+
+    #[test]
+    fn applies_target_overrides() {
+        let config: Config = toml::from_str(
+            r#"
+            [protocol]
+            network = "bandwidth"
+            max_recv_data = 2048
+
+            [protocol_targets."bank.example.com"]
+            network = "latency"
+            defer_decryption = false
+            "#,
+        )
+        .unwrap();
+
+        let tuning = config.protocol_for("bank.example.com").unwrap();
+        assert_eq!(tuning.network, NetworkMode::Latency);
+        assert!(!tuning.defer_decryption);
+        assert_eq!(tuning.max_recv_data, 2048);
+        assert_eq!(tuning.max_sent_data, MAX_SENT_DATA);
+
+        let tuning = config.protocol_for("other.example.com").unwrap();
+        assert_eq!(tuning.network, NetworkMode::Bandwidth);
+        assert!(tuning.defer_decryption);
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        let config: Config =
+            toml::from_str(r#"listen = ["0.0.0.0:9816", "[::]:9816", "unix:/run/prover.sock"]"#)
+                .unwrap();
+        assert_eq!(
+            config.listen_addresses().unwrap(),
+            vec![
+                ListenAddress::Tcp("0.0.0.0:9816".parse().unwrap()),
+                ListenAddress::Tcp("[::]:9816".parse().unwrap()),
+                ListenAddress::Unix("/run/prover.sock".into()),
+            ]
+        );
+        assert!(toml::from_str::<Config>(r#"listen = ["unix:"]"#).is_err());
+        assert!(toml::from_str::<Config>(r#"listen = ["localhost"]"#).is_err());
+
+        let config: Config = toml::from_str(r#"ws_host = "::""#).unwrap();
+        assert_eq!(
+            config.listen_addresses().unwrap(),
+            vec![ListenAddress::Tcp("[::]:9816".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn parses_trusted_proxies() {
+        let config: Config =
+            toml::from_str(r#"trusted_proxies = ["10.0.0.0/8", "127.0.0.1", "::1"]"#).unwrap();
+        assert_eq!(
+            config.trusted_proxies,
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "127.0.0.1/32".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ]
+        );
+        assert!(toml::from_str::<Config>(r#"trusted_proxies = ["proxy"]"#).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_webhook_sinks() {
+        let path = std::env::temp_dir().join(format!("prover-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"
+            [[webhooks.sinks]]
+            url = "https://hooks.example.com"
+            secret = "first"
+            [[webhooks.sinks]]
+            url = "https://hooks.example.com"
+            secret = "second"
+            "#,
+        )
+        .unwrap();
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("configured twice"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_online_limit_above_total() {
+        let overrides = TuningOverrides {
+            max_recv_data_online: Some(MAX_RECV_DATA + 1),
+            ..TuningOverrides::default()
+        };
+        assert!(ProtocolTuning::default().with(&overrides).is_err());
+    }
+}
@@ -1,29 +1,72 @@
+use admin::AdminState;
+use audit::{AuditEntry, AuditLog, AuditResult};
 use axum::{
-    extract::{Request, State},
-    response::IntoResponse,
-    routing::get,
-    Router,
+    body::Bytes,
+    extract::{FromRef, Path, Query, Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
 use axum_websocket::{WebSocket, WebSocketUpgrade};
+use chain::ClaimSubmitter;
+use config::{
+    ListenAddress, ProtocolLimits, ProtocolTuning, RedactionPolicy, SessionTimeouts,
+    TuningOverrides,
+};
 use eyre::eyre;
 use http::Uri;
 use hyper::{body::Incoming, server::conn::http1};
 use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use listener::{ConnectionSettings, Listener};
+use notary::{notarize, Notary, Reservation};
+use observer::{Event, Observers, SessionFailure, SessionObserver, VerifiedClaim};
+use peer::{ClientIp, PeerAddr};
+use rate_limit::RateLimiter;
+use reload::ConfigReloader;
+use serde::Deserialize;
+use sessions::{Session, Sessions};
 use std::{
+    collections::BTreeMap,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    path::PathBuf,
+    sync::{Arc, RwLock},
 };
-use tokio::net::TcpListener;
-use tokio::time::timeout;
+use telemetry::Phase;
+use timeouts::{count_expired, expired};
+use tls::{ListenerTls, TargetRoots};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::{JoinHandle, JoinSet};
 use tower_service::Service;
-use tracing::{debug, error, info};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+use webhook::{SessionKind, SessionStatus, WebhookDispatcher, WebhookEvent};
 use ws_stream_tungstenite::WsStream;
 
+mod admin;
+pub mod audit;
 mod axum_websocket;
+pub mod chain;
+pub mod client;
 pub mod config;
+mod listener;
+pub mod notary;
+pub mod observer;
+mod peer;
 pub mod prover;
+pub mod rate_limit;
+pub mod redaction;
+mod reload;
+pub mod sessions;
+pub mod telemetry;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+pub mod timeouts;
+pub mod tls;
+pub mod transcript;
 pub mod verifier;
+pub mod webhook;
 use prover::prover;
 use verifier::verifier;
 
@@ -31,7 +74,179 @@ use verifier::verifier;
 #[derive(Clone, Debug)]
 struct ServerGlobals {
     pub server_uri: Uri,
-    pub session_timeout: Duration,
+    pub timeouts: SessionTimeouts,
+    pub policy_version: String,
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub claim_submitter: Option<Arc<ClaimSubmitter>>,
+    pub webhooks: Option<Arc<WebhookDispatcher>>,
+    pub notary: Option<Arc<Notary>>,
+    pub target_roots: TargetRoots,
+    pub redaction: RedactionPolicy,
+    pub protocol: ProtocolTuning,
+    pub verifier_limits: ProtocolLimits,
+    pub trusted_proxies: Vec<IpNet>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub sessions: Arc<Sessions>,
+    pub observers: Observers,
+}
+
+impl ServerGlobals {
+    /// Builds the globals from `config`. Webhook deliveries start with [`WebhookDispatcher::spawn`].
+    fn load(config: &config::Config, sessions: Arc<Sessions>) -> Result<Self, eyre::ErrReport> {
+        let audit_log = match &config.audit_log_path {
+            Some(path) => {
+                let audit_log = AuditLog::open(path)?;
+                info!("Recording verification decisions to {}", path.display());
+                Some(Arc::new(audit_log))
+            }
+            None => None,
+        };
+
+        let claim_submitter = load_claim_submitter(config, None)?;
+
+        let webhooks = if config.webhooks.sinks.is_empty() {
+            None
+        } else {
+            let webhooks = Arc::new(WebhookDispatcher::new(config.webhooks.clone())?);
+            info!(
+                "Delivering session results to {} webhook sinks",
+                config.webhooks.sinks.len()
+            );
+            Some(webhooks)
+        };
+
+        let notary = load_notary(config)?.map(Arc::new);
+
+        let target_roots = load_target_roots(config)?;
+
+        Ok(Self {
+            server_uri: config.server_uri.clone(),
+            timeouts: config.timeouts,
+            policy_version: config.policy_version.clone(),
+            audit_log,
+            claim_submitter,
+            webhooks,
+            notary,
+            target_roots,
+            redaction: config.redaction.clone(),
+            protocol: config.protocol_for(&config.server_domain())?,
+            verifier_limits: config.verifier_limits.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            rate_limiter: config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit))),
+            sessions,
+            observers: Observers::default(),
+        })
+    }
+}
+
+/// Loads the claim submitter of `config`, sharing the nonces of the `previous` one.
+fn load_claim_submitter(
+    config: &config::Config,
+    previous: Option<&ClaimSubmitter>,
+) -> Result<Option<Arc<ClaimSubmitter>>, eyre::ErrReport> {
+    let Some(on_chain) = &config.on_chain else {
+        return Ok(None);
+    };
+    let mut claim_submitter = ClaimSubmitter::new(on_chain)?;
+    if let Some(previous) = previous {
+        claim_submitter.share_nonces(previous);
+    }
+    info!(
+        "Submitting verified claims to ZkOracle {} as {}",
+        on_chain.oracle_address,
+        claim_submitter.address()
+    );
+    Ok(Some(Arc::new(claim_submitter)))
+}
+
+fn load_notary(config: &config::Config) -> Result<Option<Notary>, eyre::ErrReport> {
+    let Some(notary_config) = &config.notary else {
+        return Ok(None);
+    };
+    let notary = Notary::new(notary_config)?;
+    info!(
+        "Notarizing sessions with public key {}",
+        notary.public_key()
+    );
+    Ok(Some(notary))
+}
+
+fn load_target_roots(config: &config::Config) -> Result<TargetRoots, eyre::ErrReport> {
+    let target_roots = TargetRoots::load(&config.target_tls)?;
+    if !target_roots.certs.is_empty() {
+        info!(
+            "Trusting {} extra root certificates for {}",
+            target_roots.certs.len(),
+            config.server_domain()
+        );
+    }
+    Ok(target_roots)
+}
+
+/// The current globals, replaced when the configuration is reloaded. Handlers extract a
+/// snapshot, which a session keeps until it ends.
+#[derive(Clone, Debug)]
+struct SharedGlobals(Arc<RwLock<Arc<ServerGlobals>>>);
+
+impl SharedGlobals {
+    fn new(globals: ServerGlobals) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(globals))))
+    }
+
+    fn current(&self) -> Arc<ServerGlobals> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, globals: ServerGlobals) {
+        *self.0.write().unwrap() = Arc::new(globals);
+    }
+}
+
+impl FromRef<SharedGlobals> for ServerGlobals {
+    fn from_ref(shared: &SharedGlobals) -> Self {
+        shared.current().as_ref().clone()
+    }
+}
+
+/// Query parameters accepted when opening a session
+#[derive(Clone, Debug, Deserialize)]
+struct SessionParams {
+    /// Wallet the session is proving eligibility for, recorded in the audit log
+    wallet: Option<String>,
+    /// Client-chosen UUID under which a notarized session awaits its attestation request
+    notarization_id: Option<Uuid>,
+    /// Overrides the configured network setting of a proving session
+    network: Option<config::NetworkMode>,
+    /// Overrides the configured deferred decryption of a proving session
+    defer_decryption: Option<bool>,
+    /// Overrides the configured online decryption limit of a proving session
+    max_recv_data_online: Option<usize>,
+}
+
+impl SessionParams {
+    /// Protocol settings requested for this session.
+    fn tuning_overrides(&self) -> TuningOverrides {
+        TuningOverrides {
+            network: self.network,
+            defer_decryption: self.defer_decryption,
+            max_recv_data_online: self.max_recv_data_online,
+            ..TuningOverrides::default()
+        }
+    }
+}
+
+/// Body of a policy preview request
+#[derive(Clone, Debug, Deserialize)]
+struct PreviewRequest {
+    /// Raw HTTP request, as sent to the target
+    request: String,
+    /// Raw HTTP response, as received from the target
+    response: String,
+    /// Policy to try, the configured one when unset
+    policy: Option<RedactionPolicy>,
 }
 
 /// Enum to differentiate between prover and verifier socket handling
@@ -39,110 +254,451 @@ struct ServerGlobals {
 enum SocketType {
     Prover,
     Verifier,
+    Notary,
 }
 
-pub async fn run_ws_server(config: &config::Config) -> Result<(), eyre::ErrReport> {
-    let ws_server_address = SocketAddr::new(
-        IpAddr::V4(config.ws_host.parse().map_err(|err| {
-            eyre!("Failed to parse websocket host address from server config: {err}")
-        })?),
-        config.ws_port,
-    );
-    let listener = TcpListener::bind(ws_server_address)
-        .await
-        .map_err(|err| eyre!("Failed to bind server address to tcp listener: {err}"))?;
+impl SocketType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Prover => "prover",
+            Self::Verifier => "verifier",
+            Self::Notary => "notary",
+        }
+    }
+
+    fn kind(&self) -> SessionKind {
+        match self {
+            Self::Prover => SessionKind::Prove,
+            Self::Verifier => SessionKind::Verify,
+            Self::Notary => SessionKind::Notarize,
+        }
+    }
+}
 
-    info!("Listening for TCP traffic at {}", ws_server_address);
+/// Builds the endpoints of the server, to mount them in another axum app or [`serve`] them.
+///
+/// Building starts the background tasks of the server, so it must run in a Tokio runtime.
+/// Without a [`serve`] listener, the client IP of a session comes from
+/// [`axum::extract::ConnectInfo`], when the app is served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+#[derive(Debug)]
+pub struct ServerBuilder {
+    config: config::Config,
+    config_path: Option<PathBuf>,
+    sessions: Option<Arc<Sessions>>,
+    observers: Observers,
+}
 
-    let protocol = Arc::new(http1::Builder::new());
-    let router = Router::new()
-        .route(
-            "/prove",
-            get(|ws, state| ws_handler(ws, state, SocketType::Prover)),
-        )
-        .route(
-            "/verify",
-            get(|ws, state| ws_handler(ws, state, SocketType::Verifier)),
-        )
-        .with_state(ServerGlobals {
-            server_uri: config.server_uri.clone(),
-            session_timeout: Duration::from_secs(config.session_timeout_secs),
+impl ServerBuilder {
+    pub fn new(config: config::Config) -> Self {
+        Self {
+            config,
+            config_path: None,
+            sessions: None,
+            observers: Observers::default(),
+        }
+    }
+
+    /// File `config` was loaded from, reloaded when it changes or on SIGHUP.
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Runs sessions under `sessions`, to list or shut them down from the embedding app.
+    pub fn sessions(mut self, sessions: Arc<Sessions>) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Adds `observer` to the observers of every session.
+    pub fn observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Builds routers for apps whose state is `S`, so they can be merged into their router.
+    pub fn build<S: Clone + Send + Sync + 'static>(self) -> Result<Server<S>, eyre::ErrReport> {
+        let config = self.config;
+        let sessions = self.sessions.unwrap_or_default();
+        let mut globals = ServerGlobals::load(&config, sessions.clone())?;
+        globals.observers = self.observers;
+        let globals = SharedGlobals::new(globals);
+
+        let mut tasks = Vec::new();
+        let webhooks = globals.current().webhooks.clone();
+        if let Some(webhooks) = &webhooks {
+            tasks.push(webhooks.spawn());
+        }
+        let reloader = self.config_path.map(|path| {
+            let reloader = Arc::new(ConfigReloader::new(path, config.clone(), globals.clone()));
+            tasks.push(reloader.spawn());
+            reloader
+        });
+
+        let admin_router = config.admin_token.as_ref().map(|token| {
+            admin::router(AdminState {
+                token: token.clone(),
+                sessions: sessions.clone(),
+                webhooks,
+                reloader,
+            })
         });
 
-    loop {
-        let stream = match listener.accept().await {
-            Ok((stream, _)) => stream,
+        let router = Router::new()
+            .route(
+                "/prove",
+                get(|ws, params, client_ip, state| {
+                    ws_handler(ws, params, client_ip, state, SocketType::Prover)
+                }),
+            )
+            .route(
+                "/verify",
+                get(|ws, params, client_ip, state| {
+                    ws_handler(ws, params, client_ip, state, SocketType::Verifier)
+                }),
+            )
+            .route(
+                "/notarize",
+                get(|ws, params, client_ip, state| {
+                    ws_handler(ws, params, client_ip, state, SocketType::Notary)
+                }),
+            )
+            .route("/notarize/key", get(notary_key_handler))
+            .route("/notarize/:id/attestation", post(attestation_handler))
+            .route("/policy/preview", post(policy_preview_handler))
+            .with_state(globals);
+
+        Ok(Server {
+            router,
+            admin_router,
+            sessions,
+            tasks,
+        })
+    }
+}
+
+/// Endpoints and background tasks of a built server
+#[derive(Debug)]
+pub struct Server<S = ()> {
+    /// `/prove`, `/verify`, `/notarize` and `/policy/preview`
+    pub router: Router<S>,
+    /// Admin API when `admin_token` is set, best kept off the public network
+    pub admin_router: Option<Router<S>>,
+    /// Running sessions, cancelled with [`Sessions::shutdown`]
+    pub sessions: Arc<Sessions>,
+    /// Webhook deliveries and configuration reloads, running until aborted. [`serve`] adds
+    /// its certificate reloads and HTTPS redirects.
+    pub tasks: Vec<JoinHandle<()>>,
+}
+
+/// Serves the WebSocket endpoints until the server shuts down.
+///
+/// With `config_path`, the configuration is reloaded when the file changes or on SIGHUP.
+pub async fn run_ws_server(
+    config: &config::Config,
+    config_path: Option<&std::path::Path>,
+) -> Result<(), eyre::ErrReport> {
+    let mut builder = ServerBuilder::new(config.clone());
+    if let Some(path) = config_path {
+        builder = builder.config_path(path);
+        info!("Reloading configuration from {} on change", path.display());
+    }
+    serve(config, builder.build()?).await
+}
+
+/// Serves `server` on the listeners of `config`, and its admin API on `admin_listen`.
+///
+/// On Ctrl+C or SIGTERM, running sessions are cancelled and report their result before this
+/// returns.
+pub async fn serve(config: &config::Config, mut server: Server) -> Result<(), eyre::ErrReport> {
+    let addresses = config.listen_addresses()?;
+    let mut listeners = Vec::new();
+    for address in &addresses {
+        listeners.push(Listener::bind(address).await?);
+    }
+
+    let admin_listener = match (&config.admin_listen, server.admin_router) {
+        (Some(address), Some(router)) => Some((Listener::bind(address).await?, router)),
+        (Some(_), None) => return Err(eyre!("admin_listen requires admin_token")),
+        (None, _) => None,
+    };
+
+    // Certificate reloads and redirects stop with the other tasks of the server
+    let listener_tls = match &config.listener_tls {
+        Some(tls_config) => {
+            let listener_tls = Arc::new(ListenerTls::load(tls_config)?);
+            server.tasks.push(listener_tls.spawn_reload());
+            info!(
+                "Terminating TLS with certificate {}",
+                tls_config.cert_path.display()
+            );
+            if let Some(redirect_port) = tls_config.redirect_port {
+                for address in &addresses {
+                    let ListenAddress::Tcp(https_address) = address else {
+                        continue;
+                    };
+                    let redirect_address = SocketAddr::new(https_address.ip(), redirect_port);
+                    let https_port = https_address.port();
+                    server.tasks.push(tokio::spawn(async move {
+                        if let Err(err) =
+                            tls::run_https_redirect(redirect_address, https_port).await
+                        {
+                            error!("{err}");
+                        }
+                    }));
+                }
+            }
+            Some(listener_tls)
+        }
+        None => None,
+    };
+
+    let protocol = Arc::new(http1::Builder::new());
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        servers.spawn(listener.serve(ConnectionSettings {
+            router: server.router.clone(),
+            protocol: protocol.clone(),
+            listener_tls: listener_tls.clone(),
+            proxy_protocol: config.proxy_protocol,
+        }));
+    }
+    if let Some((listener, router)) = admin_listener {
+        servers.spawn(listener.serve(ConnectionSettings {
+            router,
+            protocol: protocol.clone(),
+            listener_tls: listener_tls.clone(),
+            proxy_protocol: false,
+        }));
+    }
+    let result = tokio::select! {
+        _ = servers.join_next() => Err(eyre!("WebSocket server stopped")),
+        _ = shutdown_signal() => {
+            info!("Shutting down, cancelling running sessions");
+            servers.abort_all();
+            server.sessions.shutdown().await;
+            Ok(())
+        }
+    };
+    for task in &server.tasks {
+        task.abort();
+    }
+    result
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
             Err(err) => {
-                error!("Failed to accept TCP connection: {err}");
-                continue;
+                error!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
             }
-        };
-        debug!("Received TCP connection");
-        stream.set_nodelay(true).unwrap();
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        let tower_service = router.clone();
-        let protocol = protocol.clone();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
 
-        tokio::spawn(async move {
-            info!("Accepted TCP connection");
-            // Reference: https://github.com/tokio-rs/axum/blob/5201798d4e4d4759c208ef83e30ce85820c07baa/examples/low-level-rustls/src/main.rs#L67-L80
-            let io = TokioIo::new(stream);
+pub(crate) async fn serve_connection<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    stream: S,
+    tower_service: Router,
+    protocol: Arc<http1::Builder>,
+    peer: PeerAddr,
+) {
+    // Reference: https://github.com/tokio-rs/axum/blob/5201798d4e4d4759c208ef83e30ce85820c07baa/examples/low-level-rustls/src/main.rs#L67-L80
+    let io = TokioIo::new(stream);
 
-            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-                tower_service.clone().call(request)
-            });
-            // Serve different requests using the same hyper protocol and axum router
-            if let Err(err) = protocol
-                .serve_connection(io, hyper_service)
-                // use with_upgrades to upgrade connection to websocket for websocket clients
-                // and to extract tcp connection for tcp clients
-                .with_upgrades()
-                .await
-            {
-                error!("Connection serving failed: {err}");
-            }
-        });
+    let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(peer);
+        tower_service.clone().call(request)
+    });
+    // Serve different requests using the same hyper protocol and axum router
+    if let Err(err) = protocol
+        .serve_connection(io, hyper_service)
+        // use with_upgrades to upgrade connection to websocket for websocket clients
+        // and to extract tcp connection for tcp clients
+        .with_upgrades()
+        .await
+    {
+        error!("Connection serving failed: {err}");
     }
 }
 
+/// Submits the claim of a verified session for `wallet`, proven by its transcript digest.
+async fn submit_claim(
+    claim_submitter: &ClaimSubmitter,
+    wallet: &str,
+    claims: &BTreeMap<String, String>,
+    transcript_digest: &str,
+) -> Result<(), eyre::ErrReport> {
+    let claim_value = claim_submitter.claim_value(claims)?;
+    let proof = hex::decode(transcript_digest)
+        .map_err(|err| eyre!("Invalid transcript digest {transcript_digest}: {err}"))?;
+    claim_submitter.submit(wallet, claim_value, &proof).await?;
+    Ok(())
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    State(globals): State<ServerGlobals>,
+    Query(params): Query<SessionParams>,
+    ClientIp(client_ip): ClientIp,
+    State(mut globals): State<ServerGlobals>,
     socket_type: SocketType,
-) -> impl IntoResponse {
+) -> Response {
     let operation = match socket_type {
         SocketType::Prover => "proving",
         SocketType::Verifier => "verification",
+        SocketType::Notary => "notarization",
     };
-    info!("Received websocket request for {}", operation);
-    ws.on_upgrade(move |socket| handle_socket(socket, globals, socket_type))
+    let mut reservation = None;
+    if let SocketType::Notary = socket_type {
+        let Some(notary) = &globals.notary else {
+            return (StatusCode::NOT_FOUND, "Notarization is not enabled").into_response();
+        };
+        let Some(notarization_id) = params.notarization_id else {
+            return (StatusCode::BAD_REQUEST, "Missing notarization_id").into_response();
+        };
+        match notary.reserve(notarization_id.to_string()) {
+            Ok(reserved) => reservation = Some(reserved),
+            Err(err) => return (StatusCode::CONFLICT, err.to_string()).into_response(),
+        }
+    }
+    if let SocketType::Prover = socket_type {
+        globals.protocol = match globals.protocol.with(&params.tuning_overrides()) {
+            Ok(tuning) => tuning,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
+    }
+    let target = globals.server_uri.host().unwrap_or_default().to_string();
+    if globals.sessions.in_maintenance(&target) {
+        warn!("Rejected {operation} request: {target} is under maintenance");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{target} is under maintenance"),
+        )
+            .into_response();
+    }
+    if let (Some(rate_limiter), Some(client_ip)) = (&globals.rate_limiter, client_ip) {
+        if !rate_limiter.check(client_ip) {
+            warn!("Rejected {operation} request from {client_ip}: rate limit exceeded");
+            return (StatusCode::TOO_MANY_REQUESTS, "Too many sessions").into_response();
+        }
+    }
+    let session_id = Uuid::new_v4();
+    let client = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+    let span = info_span!(
+        "session",
+        id = %session_id,
+        socket = socket_type.as_str(),
+        target = %target,
+        client_ip = %client,
+        phase = field::Empty,
+        error_code = field::Empty,
+    );
+    span.in_scope(|| info!("Received websocket request for {operation}"));
+    let session = Session {
+        id: session_id,
+        kind: socket_type.kind(),
+        target,
+        client_ip,
+        wallet: params.wallet.clone(),
+    };
+    let sessions = globals.sessions.clone();
+    let observers = globals.observers.clone();
+    ws.on_upgrade(move |socket| {
+        sessions.track(
+            session.clone(),
+            observers
+                .observe(
+                    session,
+                    handle_socket(
+                        socket,
+                        globals,
+                        socket_type,
+                        session_id,
+                        params,
+                        client_ip,
+                        reservation,
+                    ),
+                )
+                .instrument(span),
+        )
+    })
+    .into_response()
 }
 
-async fn handle_socket(socket: WebSocket, globals: ServerGlobals, socket_type: SocketType) {
+async fn handle_socket(
+    socket: WebSocket,
+    globals: ServerGlobals,
+    socket_type: SocketType,
+    session_id: Uuid,
+    params: SessionParams,
+    client_ip: Option<IpAddr>,
+    reservation: Option<Reservation>,
+) {
     let stream = WsStream::new(socket.into_inner());
-    let session_timeout = globals.session_timeout;
+    let timeouts = &globals.timeouts;
 
     fn handle_operation_result<T>(
-        result: Result<Result<T, eyre::ErrReport>, tokio::time::error::Elapsed>,
+        result: Result<T, eyre::ErrReport>,
         operation: &str,
         on_success: impl FnOnce(T),
     ) {
         match result {
-            Ok(Ok(value)) => {
+            Ok(value) => {
                 on_success(value);
             }
-            Ok(Err(err)) => {
+            Err(err) => {
+                // Labels the session span, and so its exported trace, with the expired deadline
+                if let Some(deadline) = expired(&err) {
+                    Span::current().record("error_code", deadline.code());
+                    count_expired(deadline);
+                }
                 error!("{} failed: {err}", operation);
-            }
-            Err(elapsed) => {
-                error!("{} timed out after {:?}", operation, elapsed);
+                observer::notify(Event::Failed(SessionFailure::new(&err)));
             }
         }
     }
 
     match socket_type {
         SocketType::Prover => {
-            let result = timeout(session_timeout, prover(stream, &globals.server_uri)).await;
+            let result = globals
+                .sessions
+                .cancellable(prover(
+                    stream,
+                    &globals.server_uri,
+                    &globals.target_roots,
+                    &globals.redaction,
+                    &globals.protocol,
+                    timeouts,
+                ))
+                .await;
+            Phase::Close.enter();
+
+            if let Some(webhooks) = &globals.webhooks {
+                let target = globals.server_uri.host().unwrap_or_default().to_string();
+                let event = session_event(session_id, SessionKind::Prove, target, &params, &result);
+                webhooks.enqueue(event).await;
+            }
+
             handle_operation_result(result, "Proving", |_| {});
         }
         SocketType::Verifier => {
@@ -153,12 +709,210 @@ async fn handle_socket(socket: WebSocket, globals: ServerGlobals, socket_type: S
                 .unwrap()
                 .host();
 
-            let result = timeout(session_timeout, verifier(stream, domain)).await;
-            handle_operation_result(result, "Verification", |(sent, received)| {
+            let result = globals
+                .sessions
+                .cancellable(verifier(
+                    stream,
+                    domain,
+                    &globals.target_roots,
+                    &globals.redaction,
+                    &globals.verifier_limits,
+                    timeouts,
+                ))
+                .await;
+            Phase::Close.enter();
+
+            if let Some(audit_log) = &globals.audit_log {
+                let (result, reason, transcript_digest) = match &result {
+                    Ok(verified) => (
+                        AuditResult::Verified,
+                        None,
+                        Some(verified.transcript_digest.clone()),
+                    ),
+                    Err(err) if expired(err).is_some() => {
+                        (AuditResult::TimedOut, Some(err.to_string()), None)
+                    }
+                    Err(err) => (AuditResult::Failed, Some(err.to_string()), None),
+                };
+                let entry = AuditEntry {
+                    timestamp: AuditEntry::now(),
+                    session_id: session_id.to_string(),
+                    wallet: params.wallet.clone(),
+                    target: domain.to_string(),
+                    policy_version: globals.policy_version.clone(),
+                    result,
+                    reason,
+                    transcript_digest,
+                    client_ip: client_ip.map(|ip| ip.to_string()),
+                };
+                if let Err(err) = audit_log.append(entry).await {
+                    error!("Failed to write audit record for session {session_id}: {err}");
+                }
+            }
+
+            if let (Ok(verified), Some(claim_submitter)) = (&result, &globals.claim_submitter) {
+                match &params.wallet {
+                    // The session closes without waiting for the transaction
+                    Some(wallet) => {
+                        let claim_submitter = claim_submitter.clone();
+                        let wallet = wallet.clone();
+                        let claims = verified.claims.clone();
+                        let transcript_digest = verified.transcript_digest.clone();
+                        globals.sessions.follow_up(
+                            async move {
+                                let submitted = submit_claim(
+                                    &claim_submitter,
+                                    &wallet,
+                                    &claims,
+                                    &transcript_digest,
+                                )
+                                .await;
+                                if let Err(err) = submitted {
+                                    error!(
+                                        "Failed to submit claim for session {session_id}: {err}"
+                                    );
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                    None => warn!("Session {session_id} has no wallet, not submitting claim"),
+                }
+            }
+
+            if let Some(webhooks) = &globals.webhooks {
+                let mut event = session_event(
+                    session_id,
+                    SessionKind::Verify,
+                    domain.to_string(),
+                    &params,
+                    &result,
+                );
+                if let Ok(verified) = &result {
+                    event.transcript_digest = Some(verified.transcript_digest.clone());
+                }
+                webhooks.enqueue(event).await;
+            }
+
+            if let Ok(verified) = &result {
+                observer::notify(Event::Verified(Box::new(VerifiedClaim::new(
+                    verified,
+                    &globals.policy_version,
+                ))));
+            }
+
+            handle_operation_result(result, "Verification", |verified| {
                 info!("Successfully verified {}", domain);
-                info!("Verified sent data:\n{}", sent);
-                info!("Verified received data:\n{}", received);
+                info!("Verified sent data:\n{}", verified.transcript.sent_string());
+                info!(
+                    "Verified received data:\n{}",
+                    verified.transcript.received_string()
+                );
+            });
+        }
+        SocketType::Notary => {
+            let (Some(reservation), Some(notarization_id)) = (reservation, params.notarization_id)
+            else {
+                return;
+            };
+            let result = globals
+                .sessions
+                .cancellable(notarize(
+                    stream,
+                    &globals.target_roots,
+                    &globals.verifier_limits,
+                    timeouts,
+                ))
+                .await;
+            Phase::Close.enter();
+
+            if let Some(webhooks) = &globals.webhooks {
+                let target = globals.server_uri.host().unwrap_or_default().to_string();
+                let event =
+                    session_event(session_id, SessionKind::Notarize, target, &params, &result);
+                webhooks.enqueue(event).await;
+            }
+
+            handle_operation_result(result, "Notarization", |session| {
+                info!("Notarized session {notarization_id}, awaiting attestation request");
+                reservation.insert(session);
             });
         }
     }
 }
+
+/// Builds the webhook event describing how a session ended.
+fn session_event<T>(
+    session_id: Uuid,
+    kind: SessionKind,
+    target: String,
+    params: &SessionParams,
+    result: &Result<T, eyre::ErrReport>,
+) -> WebhookEvent {
+    let (status, error, error_code) = match result {
+        Ok(_) => (SessionStatus::Succeeded, None, None),
+        Err(err) => match expired(err) {
+            Some(deadline) => (
+                SessionStatus::TimedOut,
+                Some(err.to_string()),
+                Some(deadline.code().to_string()),
+            ),
+            None => (SessionStatus::Failed, Some(err.to_string()), None),
+        },
+    };
+    let mut event = WebhookEvent::new(session_id.to_string(), kind, status, target);
+    event.wallet = params.wallet.clone();
+    event.error = error;
+    event.error_code = error_code;
+    event
+}
+
+/// Applies a redaction policy to a sample transcript, without running MPC-TLS.
+async fn policy_preview_handler(
+    State(globals): State<ServerGlobals>,
+    Json(preview): Json<PreviewRequest>,
+) -> Response {
+    let policy = preview.policy.as_ref().unwrap_or(&globals.redaction);
+    match redaction::preview(
+        policy,
+        preview.request.as_bytes(),
+        preview.response.as_bytes(),
+    ) {
+        Ok(preview) => Json(preview).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// Returns the public key that attestations are signed with.
+async fn notary_key_handler(State(globals): State<ServerGlobals>) -> Response {
+    match &globals.notary {
+        Some(notary) => Json(serde_json::json!({
+            "algorithm": "secp256k1",
+            "public_key": notary.public_key(),
+        }))
+        .into_response(),
+        None => (StatusCode::NOT_FOUND, "Notarization is not enabled").into_response(),
+    }
+}
+
+/// Signs an attestation for a notarized session, given the prover's bincode-encoded request.
+async fn attestation_handler(
+    Path(notarization_id): Path<Uuid>,
+    State(globals): State<ServerGlobals>,
+    request: Bytes,
+) -> Response {
+    let Some(notary) = &globals.notary else {
+        return (StatusCode::NOT_FOUND, "Notarization is not enabled").into_response();
+    };
+    match notary.attest(&notarization_id.to_string(), &request) {
+        Ok(attestation) => (
+            [(http::header::CONTENT_TYPE, "application/octet-stream")],
+            attestation,
+        )
+            .into_response(),
+        Err(err) => {
+            error!("Attestation failed: {err}");
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+    }
+}
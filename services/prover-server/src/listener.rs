@@ -0,0 +1,208 @@
+//! Listeners of the WebSocket server, on TCP or Unix domain sockets.
+//!
+//! Every listener serves the same router, terminating TLS first when configured.
+
+use crate::{
+    config::ListenAddress,
+    peer::{read_proxy_header, PeerAddr},
+    serve_connection,
+    tls::ListenerTls,
+};
+use axum::Router;
+use eyre::eyre;
+use hyper::server::conn::http1;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time::timeout,
+};
+use tracing::{debug, error, info, info_span, Instrument};
+
+/// How long a connection has to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-connection settings shared by all listeners
+#[derive(Clone)]
+pub(crate) struct ConnectionSettings {
+    pub router: Router,
+    pub protocol: Arc<http1::Builder>,
+    pub listener_tls: Option<Arc<ListenerTls>>,
+    /// Read the client address from a PROXY protocol header
+    pub proxy_protocol: bool,
+}
+
+/// A bound listener, accepting connections once [`Listener::serve`] runs
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Binds `address`. A stale Unix socket file left by a previous run is replaced.
+    pub(crate) async fn bind(address: &ListenAddress) -> Result<Self, eyre::ErrReport> {
+        let listener = match address {
+            ListenAddress::Tcp(socket_address) => bind_tcp(*socket_address)
+                .map(Self::Tcp)
+                .map_err(|err| eyre!("Failed to bind {address}: {err}"))?,
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                let stale = std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket());
+                if stale {
+                    std::fs::remove_file(path)
+                        .map_err(|err| eyre!("Failed to remove stale socket {address}: {err}"))?;
+                }
+                tokio::net::UnixListener::bind(path)
+                    .map(Self::Unix)
+                    .map_err(|err| eyre!("Failed to bind {address}: {err}"))?
+            }
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => {
+                return Err(eyre!("Unix sockets are not supported on this platform"))
+            }
+        };
+        info!("Listening for connections at {address}");
+        Ok(listener)
+    }
+
+    /// Accepts connections forever, serving each of them on its own task.
+    pub(crate) async fn serve(self, settings: ConnectionSettings) {
+        match self {
+            Self::Tcp(listener) => loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        debug!("Received TCP connection from {peer}");
+                        if let Err(err) = stream.set_nodelay(true) {
+                            error!("Failed to set TCP_NODELAY: {err}");
+                        }
+                        tokio::spawn(handle_connection(stream, Some(peer), settings.clone()));
+                    }
+                    Err(err) => error!("Failed to accept TCP connection: {err}"),
+                }
+            },
+            #[cfg(unix)]
+            Self::Unix(listener) => loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        debug!("Received Unix socket connection");
+                        tokio::spawn(handle_connection(stream, None, settings.clone()));
+                    }
+                    Err(err) => error!("Failed to accept Unix socket connection: {err}"),
+                }
+            },
+        }
+    }
+}
+
+/// Binds a TCP listener. IPv6 addresses accept IPv6 only, so `[::]` and `0.0.0.0` can listen
+/// on the same port side by side whatever `net.ipv6.bindv6only` is set to.
+fn bind_tcp(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Like `TcpListener::bind`, so restarts don't wait for old connections to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    mut stream: S,
+    mut peer: Option<SocketAddr>,
+    settings: ConnectionSettings,
+) {
+    if settings.proxy_protocol {
+        match timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+            // Health checks of the proxy itself have no client address
+            Ok(Ok(source)) => peer = source.or(peer),
+            Ok(Err(err)) => return error!("Rejected connection from {peer:?}: {err}"),
+            Err(_) => return error!("No PROXY protocol header from {peer:?}"),
+        }
+    }
+    let peer = PeerAddr(peer);
+    let span = info_span!("connection", peer = %peer);
+    span.in_scope(|| info!("Accepted connection"));
+
+    let ConnectionSettings {
+        router,
+        protocol,
+        listener_tls,
+        ..
+    } = settings;
+    async move {
+        match listener_tls {
+            Some(listener_tls) => match listener_tls.acceptor().accept(stream).await {
+                Ok(stream) => serve_connection(stream, router, protocol, peer).await,
+                Err(err) => error!("TLS handshake failed: {err}"),
+            },
+            None => serve_connection(stream, router, protocol, peer).await,
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn binds_ipv4_and_ipv6_on_the_same_port() {
+        // Nothing to test on hosts without IPv6
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let Listener::Tcp(ipv4) = Listener::bind(&ListenAddress::Tcp("0.0.0.0:0".parse().unwrap()))
+            .await
+            .unwrap()
+        else {
+            unreachable!()
+        };
+        let port = ipv4.local_addr().unwrap().port();
+        let ipv6 = ListenAddress::Tcp(SocketAddr::from(([0u16; 8], port)));
+        Listener::bind(&ipv6).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("listener-{}.sock", uuid::Uuid::new_v4()));
+        let address = ListenAddress::Unix(path.clone());
+        // The socket file left by a previous run doesn't prevent binding
+        drop(Listener::bind(&address).await.unwrap());
+        let listener = Listener::bind(&address).await.unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let server = tokio::spawn(listener.serve(ConnectionSettings {
+            router,
+            protocol: Arc::new(http1::Builder::new()),
+            listener_tls: None,
+            proxy_protocol: false,
+        }));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        server.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
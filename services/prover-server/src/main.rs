@@ -1,37 +1,276 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
-use server::{config::Config, run_ws_server};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use clap::{Parser, Subcommand, ValueEnum};
+use server::{
+    audit, client,
+    config::{Config, RedactionPolicy},
+    notary::{self, Notary, PresentationBundle},
+    redaction, run_ws_server, telemetry,
+    tls::TargetRoots,
+    verifier,
+};
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
 use wstcp::ProxyServer;
 
 const TRACING_FILTER: &str = "INFO";
 
+#[derive(Parser)]
+#[command(about = "TLSNotary prover and verifier server")]
+struct Cli {
+    /// Path to a TOML configuration file; defaults are used when omitted
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Format of the log lines
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with the fields of the session and connection spans
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the hash chain of an audit log is intact
+    VerifyAuditLog {
+        /// Audit log to check; defaults to `audit_log_path` from the configuration
+        path: Option<PathBuf>,
+    },
+    /// Verify a saved presentation bundle offline and print the extracted claim as JSON
+    VerifyBundle {
+        /// Presentation bundle built from a notary attestation
+        path: PathBuf,
+        /// Hex-encoded notary public key; defaults to the key of the configured notary
+        #[arg(long)]
+        notary_key: Option<String>,
+    },
+    /// Prove data from the configured target to a remote `/verify` endpoint and print the outcome as JSON
+    Prove {
+        /// WebSocket URL of the verifier, e.g. `wss://example.com/verify`
+        verifier_url: String,
+        /// Wallet the proof is for, passed on to the verifier
+        #[arg(long)]
+        wallet: Option<String>,
+    },
+    /// Apply a redaction policy to a raw HTTP request and response and print what would be revealed
+    PreviewPolicy {
+        /// Raw HTTP request, as sent to the target
+        request: PathBuf,
+        /// Raw HTTP response, as received from the target
+        response: PathBuf,
+        /// TOML file with the keys of the `[redaction]` table; defaults to the configured policy
+        #[arg(long)]
+        policy: Option<PathBuf>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), eyre::ErrReport> {
+    let cli = Cli::parse();
+
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // Keep stdout for the output of subcommands
+    let log_writer = match cli.command {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let log_layer = match cli.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(log_writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(log_writer)
+            .boxed(),
+    };
+    let (otlp_layer, tracer_provider, meter_provider) = match &config.telemetry {
+        Some(telemetry) => {
+            let (layer, provider) = telemetry::otlp_layer(telemetry)?;
+            let meter_provider = telemetry::otlp_meter_provider(telemetry)?;
+            (Some(layer), Some(provider), Some(meter_provider))
+        }
+        None => (None, None, None),
+    };
     tracing_subscriber::registry()
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| TRACING_FILTER.into()))
-        .with(tracing_subscriber::fmt::layer())
+        .with(log_layer)
+        .with(otlp_layer)
         .init();
 
-    let config: Config = Config::default();
+    let result = run(cli.command, &config, cli.config.as_deref()).await;
+
+    // Flush the spans still batched for export
+    if let Some(provider) = tracer_provider {
+        let shutdown = tokio::task::spawn_blocking(move || provider.shutdown()).await?;
+        if let Err(err) = shutdown {
+            eprintln!("Failed to flush traces: {err}");
+        }
+    }
+    if let Some(provider) = meter_provider {
+        let shutdown = tokio::task::spawn_blocking(move || provider.shutdown()).await?;
+        if let Err(err) = shutdown {
+            eprintln!("Failed to flush metrics: {err}");
+        }
+    }
+
+    result
+}
+
+async fn run(
+    command: Option<Command>,
+    config: &Config,
+    config_path: Option<&Path>,
+) -> Result<(), eyre::ErrReport> {
+    match command {
+        Some(Command::VerifyAuditLog { path }) => return verify_audit_log(path, config),
+        Some(Command::VerifyBundle { path, notary_key }) => {
+            return verify_bundle(path, notary_key, config)
+        }
+        Some(Command::Prove {
+            verifier_url,
+            wallet,
+        }) => return prove(verifier_url, wallet, config).await,
+        Some(Command::PreviewPolicy {
+            request,
+            response,
+            policy,
+        }) => return preview_policy(request, response, policy, config),
+        None => {}
+    }
 
     // Start wstcp proxy subprocess in background
 
-    // Run both servers in parallel
-    let (ws_result, proxy_result) =
-        tokio::join!(run_ws_server(&config), run_wstcp_proxy_async(&config));
+    // Run both servers in parallel, until either fails or the WebSocket server shuts down
+    tokio::select! {
+        result = run_ws_server(config, config_path) => result,
+        result = run_wstcp_proxy_async(config) => result,
+    }
+}
+
+fn verify_audit_log(path: Option<PathBuf>, config: &Config) -> Result<(), eyre::ErrReport> {
+    let path = path
+        .or_else(|| config.audit_log_path.clone())
+        .ok_or_else(|| eyre::eyre!("No audit log path given and none configured"))?;
 
-    // Handle results - if either fails, propagate the error
-    ws_result?;
-    proxy_result?;
+    let summary = audit::verify_log(&path)?;
+    println!(
+        "{}: {} records, chain intact (head {})",
+        path.display(),
+        summary.records,
+        summary.head
+    );
 
     Ok(())
 }
 
+fn verify_bundle(
+    path: PathBuf,
+    notary_key: Option<String>,
+    config: &Config,
+) -> Result<(), eyre::ErrReport> {
+    let notary_key = match (notary_key, &config.notary) {
+        (Some(notary_key), _) => notary_key,
+        (None, Some(notary_config)) => Notary::new(notary_config)?.public_key(),
+        (None, None) => return Err(eyre::eyre!("No notary key given and no notary configured")),
+    };
+    let contents = std::fs::read(&path)
+        .map_err(|err| eyre::eyre!("Failed to read {}: {err}", path.display()))?;
+    let bundle: PresentationBundle = serde_json::from_slice(&contents)
+        .map_err(|err| eyre::eyre!("Malformed presentation bundle {}: {err}", path.display()))?;
+
+    let result = notary::verify_presentation(&bundle, &notary_key).and_then(|presentation| {
+        verifier::verify_transcript(
+            presentation.transcript,
+            &config.server_domain(),
+            &config.redaction,
+        )
+        .map(|claim| (presentation.time, claim))
+    });
+
+    match result {
+        Ok((time, claim)) => {
+            let output = serde_json::json!({ "valid": true, "time": time, "claim": claim });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            Ok(())
+        }
+        Err(err) => {
+            let output = serde_json::json!({ "valid": false, "error": err.to_string() });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            // Exits with a failure status once traces are flushed
+            Err(err.wrap_err("Presentation bundle is invalid"))
+        }
+    }
+}
+
+async fn prove(
+    verifier_url: String,
+    wallet: Option<String>,
+    config: &Config,
+) -> Result<(), eyre::ErrReport> {
+    let outcome = client::prove_to_verifier(
+        &verifier_url,
+        wallet.as_deref(),
+        &config.server_uri,
+        &TargetRoots::load(&config.target_tls)?,
+        &config.redaction,
+        &config.protocol_for(&config.server_domain())?,
+        &config.timeouts,
+    )
+    .await;
+
+    println!("{}", serde_json::to_string_pretty(&outcome)?);
+    if !outcome.success {
+        return Err(eyre::eyre!("Proving to {verifier_url} failed"));
+    }
+    Ok(())
+}
+
+fn preview_policy(
+    request: PathBuf,
+    response: PathBuf,
+    policy: Option<PathBuf>,
+    config: &Config,
+) -> Result<(), eyre::ErrReport> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).map_err(|err| eyre::eyre!("Failed to read {}: {err}", path.display()))
+    };
+    let policy = match policy {
+        Some(path) => toml::from_str::<RedactionPolicy>(&String::from_utf8(read(&path)?)?)
+            .map_err(|err| eyre::eyre!("Failed to parse policy {}: {err}", path.display()))?,
+        None => config.redaction.clone(),
+    };
+
+    let preview = redaction::preview(&policy, &read(&request)?, &read(&response)?)?;
+    println!("{}", serde_json::to_string_pretty(&preview)?);
+    Ok(())
+}
+
 async fn run_wstcp_proxy_async(config: &Config) -> Result<(), eyre::ErrReport> {
-    let bind_addr = SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        config.wstcp_proxy_port,
-    );
+    let host: IpAddr = config.wstcp_proxy_host.parse().map_err(|err| {
+        eyre::eyre!(
+            "Failed to parse wstcp proxy host address {}: {err}",
+            config.wstcp_proxy_host
+        )
+    })?;
+    let bind_addr = SocketAddr::new(host, config.wstcp_proxy_port);
     let tcp_server_addr = format!("{}:443", config.server_domain())
         .to_socket_addrs()?
         .next()
@@ -0,0 +1,410 @@
+//! Notarization mode, where the server acts as notary instead of live verifier.
+//!
+//! The prover runs MPC-TLS against `/notarize`, committing to its transcript instead of
+//! revealing it. It then posts its attestation request to `/notarize/{id}/attestation`
+//! and receives an [`Attestation`] signed with the notary key. From the attestation the
+//! user builds a [`Presentation`], which anyone can check offline with
+//! [`verify_presentation`] against the notary's public key.
+
+use crate::{
+    config::{NotaryConfig, ProtocolLimits, SessionTimeouts},
+    telemetry::Phase,
+    timeouts::{within, Deadline},
+    tls::TargetRoots,
+    transcript::AuthenticatedTranscript,
+    verifier::verifier_config,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::eyre;
+use k256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tlsn::{
+    attestation::{
+        presentation::{Presentation, PresentationOutput},
+        request::Request as AttestationRequest,
+        signing::Secp256k1Signer,
+        Attestation, AttestationConfig, CryptoProvider,
+    },
+    connection::{ConnectionInfo, ServerEphemKey, ServerName, TranscriptLength},
+    transcript::{ContentType, TranscriptCommitment},
+    verifier::{Verifier, VerifierOutput, VerifyConfig},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tracing::{debug, info, Instrument};
+
+/// Version of the [`PresentationBundle`] format
+pub const PRESENTATION_BUNDLE_VERSION: u32 = 1;
+
+/// What the notary learned from an MPC-TLS session, kept until the attestation request arrives
+#[derive(Debug)]
+pub struct NotarizedSession {
+    connection_info: ConnectionInfo,
+    server_ephemeral_key: ServerEphemKey,
+    transcript_commitments: Vec<TranscriptCommitment>,
+}
+
+/// Notarized session awaiting its attestation request
+#[derive(Debug)]
+struct PendingAttestation {
+    notarized_at: Instant,
+    /// Key of the notary when the session started
+    signing_key: SigningKey,
+    session: NotarizedSession,
+}
+
+/// Notary that signs attestations for completed sessions.
+#[derive(Debug)]
+pub struct Notary {
+    signing_key: RwLock<SigningKey>,
+    request_timeout: RwLock<Duration>,
+    sessions: Mutex<HashMap<String, PendingAttestation>>,
+    /// IDs of sessions being notarized
+    reserved: Mutex<HashSet<String>>,
+}
+
+impl Notary {
+    pub fn new(config: &NotaryConfig) -> Result<Self, eyre::ErrReport> {
+        let key_bytes = hex::decode(config.private_key.trim_start_matches("0x"))
+            .map_err(|err| eyre!("Invalid notary private key: {err}"))?;
+        let signing_key = SigningKey::from_slice(&key_bytes)
+            .map_err(|err| eyre!("Invalid notary private key: {err}"))?;
+
+        Ok(Self {
+            signing_key: RwLock::new(signing_key),
+            request_timeout: RwLock::new(Duration::from_secs(config.request_timeout_secs)),
+            sessions: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Takes over the key and timeout of `reloaded`, keeping the sessions awaiting their
+    /// attestation request. Sessions that already started are attested with the key they
+    /// started with, which is the one their prover was shown.
+    pub fn update(&self, reloaded: Notary) {
+        *self.signing_key.write().unwrap() = reloaded.signing_key.into_inner().unwrap();
+        *self.request_timeout.write().unwrap() = reloaded.request_timeout.into_inner().unwrap();
+    }
+
+    /// Hex-encoded, compressed secp256k1 public key that attestations are signed with.
+    pub fn public_key(&self) -> String {
+        public_key(&self.signing_key.read().unwrap())
+    }
+
+    /// Reserves `id` for a session about to be notarized. The prover chooses the ID, so it
+    /// is rejected while a session with the same ID is running or awaits its attestation
+    /// request.
+    pub fn reserve(self: &Arc<Self>, id: String) -> Result<Reservation, eyre::ErrReport> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.prune(&mut sessions);
+        if sessions.contains_key(&id) || !self.reserved.lock().unwrap().insert(id.clone()) {
+            return Err(eyre!("Notarization ID {id} is already in use"));
+        }
+        Ok(Reservation {
+            notary: self.clone(),
+            id,
+            signing_key: self.signing_key.read().unwrap().clone(),
+        })
+    }
+
+    /// Signs an attestation for the session `id`, which can only be attested once.
+    pub fn attest(&self, id: &str, request: &[u8]) -> Result<Vec<u8>, eyre::ErrReport> {
+        let pending = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let pending = sessions.remove(id);
+            self.prune(&mut sessions);
+            pending
+        };
+        let PendingAttestation {
+            notarized_at,
+            signing_key,
+            session,
+        } = pending.ok_or_else(|| eyre!("No notarized session {id}"))?;
+        if notarized_at.elapsed() >= *self.request_timeout.read().unwrap() {
+            return Err(eyre!("Notarized session {id} has expired"));
+        }
+
+        let request: AttestationRequest = bincode::deserialize(request)
+            .map_err(|err| eyre!("Malformed attestation request: {err}"))?;
+
+        let mut provider = CryptoProvider::default();
+        provider.signer.set_signer(Box::new(
+            Secp256k1Signer::new(&signing_key.to_bytes())
+                .map_err(|err| eyre!("Failed to load notary key: {err}"))?,
+        ));
+
+        let mut config_builder = AttestationConfig::builder();
+        config_builder.supported_signature_algs(Vec::from_iter(provider.signer.supported_algs()));
+        let config = config_builder
+            .build()
+            .map_err(|err| eyre!("Failed to build attestation config: {err}"))?;
+
+        let mut builder = Attestation::builder(&config)
+            .accept_request(request)
+            .map_err(|err| eyre!("Attestation request rejected: {err}"))?;
+        builder
+            .connection_info(session.connection_info)
+            .server_ephemeral_key(session.server_ephemeral_key)
+            .transcript_commitments(session.transcript_commitments);
+        let attestation = builder
+            .build(&provider)
+            .map_err(|err| eyre!("Failed to build attestation: {err}"))?;
+
+        info!("Signed attestation for notarized session {id}");
+        Ok(bincode::serialize(&attestation)?)
+    }
+
+    /// Drops the sessions whose attestation request didn't arrive in time. Runs whenever
+    /// sessions are reserved, kept or attested, so abandoned ones don't pile up.
+    fn prune(&self, sessions: &mut HashMap<String, PendingAttestation>) {
+        let request_timeout = *self.request_timeout.read().unwrap();
+        sessions.retain(|_, pending| pending.notarized_at.elapsed() < request_timeout);
+    }
+}
+
+/// Notarization ID reserved for a running session, released when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    notary: Arc<Notary>,
+    id: String,
+    /// Key the session is attested with, even if the notary key is reloaded meanwhile
+    signing_key: SigningKey,
+}
+
+impl Reservation {
+    /// Keeps `session` until the prover sends its attestation request.
+    pub fn insert(self, session: NotarizedSession) {
+        let pending = PendingAttestation {
+            notarized_at: Instant::now(),
+            signing_key: self.signing_key.clone(),
+            session,
+        };
+        let mut sessions = self.notary.sessions.lock().unwrap();
+        self.notary.prune(&mut sessions);
+        sessions.insert(self.id.clone(), pending);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.notary.reserved.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Hex-encoded, compressed secp256k1 public key of `signing_key`.
+fn public_key(signing_key: &SigningKey) -> String {
+    hex::encode(
+        signing_key
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes(),
+    )
+}
+
+/// Runs the notary side of MPC-TLS with the prover on `socket`.
+pub async fn notarize<T: AsyncWrite + AsyncRead + Send + Unpin + 'static>(
+    socket: T,
+    roots: &TargetRoots,
+    limits: &ProtocolLimits,
+    timeouts: &SessionTimeouts,
+) -> Result<NotarizedSession, eyre::ErrReport> {
+    debug!("Starting notarization...");
+
+    let verifier = within(
+        Deadline::Setup,
+        timeouts.get(Deadline::Setup),
+        Verifier::new(verifier_config(roots, limits)?)
+            .setup(socket.compat())
+            .instrument(Phase::Setup.span()),
+    )
+    .await?
+    .map_err(|e| eyre!("Notarization setup failed: {}", e))?;
+
+    // The prover's connection to the target, from the handshake to the response
+    let mut verifier = within(
+        Deadline::Response,
+        timeouts.upstream(),
+        verifier.run().instrument(Phase::Request.span()),
+    )
+    .await?
+    .map_err(|e| eyre!("Notarization failed: {}", e))?;
+
+    let (
+        VerifierOutput {
+            transcript_commitments,
+            ..
+        },
+        tls_transcript,
+    ) = within(
+        Deadline::Finalize,
+        timeouts.get(Deadline::Finalize),
+        async {
+            let prove_span = Phase::Prove.span();
+            let output = verifier
+                .verify(&VerifyConfig::default())
+                .instrument(prove_span.clone())
+                .await
+                .map_err(|e| eyre!("Notarization failed: {}", e))?;
+
+            let tls_transcript = verifier.tls_transcript().clone();
+            // `handle_socket` enters the close phase once the session ended, however it ended
+            verifier
+                .close()
+                .instrument(prove_span)
+                .await
+                .map_err(|e| eyre!("Failed to close notarization session: {}", e))?;
+            Ok::<_, eyre::ErrReport>((output, tls_transcript))
+        },
+    )
+    .await??;
+
+    let sent_len = tls_transcript
+        .sent()
+        .iter()
+        .filter(|record| matches!(record.typ, ContentType::ApplicationData))
+        .map(|record| record.ciphertext.len())
+        .sum::<usize>();
+    let recv_len = tls_transcript
+        .recv()
+        .iter()
+        .filter(|record| matches!(record.typ, ContentType::ApplicationData))
+        .map(|record| record.ciphertext.len())
+        .sum::<usize>();
+
+    Ok(NotarizedSession {
+        connection_info: ConnectionInfo {
+            time: tls_transcript.time(),
+            version: *tls_transcript.version(),
+            transcript_length: TranscriptLength {
+                sent: sent_len as u32,
+                received: recv_len as u32,
+            },
+        },
+        server_ephemeral_key: tls_transcript.server_ephemeral_key().clone(),
+        transcript_commitments,
+    })
+}
+
+/// Portable file format for presentations, shown by users to third-party verifiers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PresentationBundle {
+    pub version: u32,
+    /// Base64 of the bincode-encoded [`Presentation`]
+    pub presentation: String,
+}
+
+impl PresentationBundle {
+    pub fn new(presentation: &Presentation) -> Result<Self, eyre::ErrReport> {
+        Ok(Self {
+            version: PRESENTATION_BUNDLE_VERSION,
+            presentation: STANDARD.encode(bincode::serialize(presentation)?),
+        })
+    }
+}
+
+/// Data proven by a presentation
+#[derive(Clone, Debug)]
+pub struct VerifiedPresentation {
+    /// Unix time of the TLS connection
+    pub time: u64,
+    pub transcript: AuthenticatedTranscript,
+}
+
+/// Verifies a [`PresentationBundle`] against the hex-encoded notary public key.
+pub fn verify_presentation(
+    bundle: &PresentationBundle,
+    notary_public_key: &str,
+) -> Result<VerifiedPresentation, eyre::ErrReport> {
+    if bundle.version != PRESENTATION_BUNDLE_VERSION {
+        return Err(eyre!(
+            "Unsupported presentation bundle version {}",
+            bundle.version
+        ));
+    }
+    let presentation: Presentation = bincode::deserialize(
+        &STANDARD
+            .decode(&bundle.presentation)
+            .map_err(|err| eyre!("Presentation is not valid base64: {err}"))?,
+    )
+    .map_err(|err| eyre!("Malformed presentation: {err}"))?;
+
+    let expected_key = hex::decode(notary_public_key.trim_start_matches("0x"))
+        .map_err(|err| eyre!("Invalid notary public key: {err}"))?;
+    if presentation.verifying_key().data != expected_key {
+        return Err(eyre!(
+            "Presentation was signed by {}, not by the trusted notary",
+            hex::encode(&presentation.verifying_key().data)
+        ));
+    }
+
+    let PresentationOutput {
+        server_name,
+        connection_info,
+        transcript,
+        ..
+    } = presentation
+        .verify(&CryptoProvider::default())
+        .map_err(|err| eyre!("Presentation verification failed: {err}"))?;
+
+    let ServerName::Dns(server_name) =
+        server_name.ok_or_else(|| eyre!("Presentation does not reveal the server name"))?;
+    let mut transcript =
+        transcript.ok_or_else(|| eyre!("Presentation does not reveal transcript data"))?;
+    transcript.set_unauthed(0);
+
+    Ok(VerifiedPresentation {
+        time: connection_info.time,
+        transcript: AuthenticatedTranscript {
+            server_name: server_name.as_str().to_string(),
+            sent: transcript.sent_unsafe().to_vec(),
+            received: transcript.received_unsafe().to_vec(),
+            sent_authed: transcript.sent_authed().clone(),
+            received_authed: transcript.received_authed().clone(),
+            timestamp: connection_info.time * 1000,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_notarization_ids_in_use() {
+        let notary = Arc::new(
+            Notary::new(&NotaryConfig {
+                private_key: "01".repeat(32),
+                ..NotaryConfig::default()
+            })
+            .unwrap(),
+        );
+
+        let reservation = notary.reserve("session".into()).unwrap();
+        assert!(notary.reserve("session".into()).is_err());
+        assert!(notary.reserve("other".into()).is_ok());
+        drop(reservation);
+        assert!(notary.reserve("session".into()).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_key_of_reserved_sessions_on_reload() {
+        let config = |key: &str| NotaryConfig {
+            private_key: key.repeat(32),
+            ..NotaryConfig::default()
+        };
+        let notary = Arc::new(Notary::new(&config("01")).unwrap());
+        let advertised = notary.public_key();
+
+        let reservation = notary.reserve("session".into()).unwrap();
+        notary.update(Notary::new(&config("02")).unwrap());
+        assert_ne!(notary.public_key(), advertised);
+        assert_eq!(public_key(&reservation.signing_key), advertised);
+        let reserved_later = notary.reserve("later".into()).unwrap();
+        assert_eq!(public_key(&reserved_later.signing_key), notary.public_key());
+    }
+}
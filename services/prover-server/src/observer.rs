@@ -0,0 +1,278 @@
+//! Hooks into the lifecycle of sessions, for business logic like CRM updates or fraud
+//! checks.
+//!
+//! Observers are registered with [`ServerBuilder::observer`](crate::ServerBuilder::observer).
+//! Each session delivers its events from its own task, in order, observer after observer.
+//! The session doesn't wait for its observers, and an observer that panics only misses the
+//! event it panicked on.
+
+use crate::{
+    sessions::Session,
+    telemetry::Phase,
+    timeouts::{expired, Deadline},
+    transcript::AuthenticatedTranscript,
+    verifier::VerifiedData,
+};
+use async_trait::async_trait;
+use futures::FutureExt;
+use std::{collections::BTreeMap, fmt, future::Future, panic::AssertUnwindSafe, sync::Arc};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::{error, Instrument};
+
+tokio::task_local! {
+    /// Events of the session being polled
+    static EVENTS: UnboundedSender<Event>;
+}
+
+/// Callbacks on the lifecycle of a session. Every callback defaults to doing nothing.
+#[async_trait]
+pub trait SessionObserver: Send + Sync {
+    /// The WebSocket of `session` was upgraded.
+    async fn on_connect(&self, _session: &Session) {}
+
+    /// `session` entered `phase`.
+    async fn on_phase(&self, _session: &Session, _phase: Phase) {}
+
+    /// The verifier authenticated the transcript of `session`.
+    async fn on_verified(&self, _session: &Session, _claim: &VerifiedClaim) {}
+
+    /// `session` failed, timed out or was cancelled.
+    async fn on_failed(&self, _session: &Session, _failure: &SessionFailure) {}
+
+    /// `session` ended, always the last event.
+    async fn on_closed(&self, _session: &Session) {}
+}
+
+/// Transcript data authenticated by the verifier
+#[derive(Clone, Debug)]
+pub struct VerifiedClaim {
+    pub policy_version: String,
+    pub transcript: AuthenticatedTranscript,
+    /// Hex-encoded digest of the authenticated bytes, as recorded in the audit log
+    pub transcript_digest: String,
+    /// Named capture groups of the policy's regex rules
+    pub claims: BTreeMap<String, String>,
+}
+
+impl VerifiedClaim {
+    pub(crate) fn new(verified: &VerifiedData, policy_version: &str) -> Self {
+        Self {
+            policy_version: policy_version.to_string(),
+            transcript: verified.transcript.clone(),
+            transcript_digest: verified.transcript_digest.clone(),
+            claims: verified.claims.clone(),
+        }
+    }
+}
+
+/// Why a session failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionFailure {
+    pub error: String,
+    /// Deadline the session missed, if it timed out
+    pub expired: Option<Deadline>,
+}
+
+impl SessionFailure {
+    pub(crate) fn new(err: &eyre::ErrReport) -> Self {
+        Self {
+            error: err.to_string(),
+            expired: expired(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Event {
+    Connect,
+    Phase(Phase),
+    Verified(Box<VerifiedClaim>),
+    Failed(SessionFailure),
+    Closed,
+}
+
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Phase(_) => "phase",
+            Self::Verified(_) => "verified",
+            Self::Failed(_) => "failed",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+/// Observers registered with the server
+#[derive(Clone, Default)]
+pub(crate) struct Observers(Arc<Vec<Arc<dyn SessionObserver>>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub fn push(&mut self, observer: Arc<dyn SessionObserver>) {
+        Arc::make_mut(&mut self.0).push(observer);
+    }
+
+    /// Runs `future` as `session`, delivering its events to the observers until it ends.
+    pub async fn observe<F: Future>(self, session: Session, future: F) -> F::Output {
+        // Without observers nobody would read the events
+        if self.0.is_empty() {
+            return future.await;
+        }
+        let (sender, mut receiver) = unbounded_channel();
+        let observers = self.0;
+        tokio::spawn(
+            async move {
+                while let Some(event) = receiver.recv().await {
+                    for observer in observers.iter() {
+                        deliver(observer.as_ref(), &session, &event).await;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        let _ = sender.send(Event::Connect);
+        let _closed = Closed(sender.clone());
+        EVENTS.scope(sender, future).await
+    }
+}
+
+async fn deliver(observer: &dyn SessionObserver, session: &Session, event: &Event) {
+    let callback = match event {
+        Event::Connect => observer.on_connect(session),
+        Event::Phase(phase) => observer.on_phase(session, *phase),
+        Event::Verified(claim) => observer.on_verified(session, claim),
+        Event::Failed(failure) => observer.on_failed(session, failure),
+        Event::Closed => observer.on_closed(session),
+    };
+    if AssertUnwindSafe(callback).catch_unwind().await.is_err() {
+        error!(
+            "Session observer panicked on the {} event of session {}",
+            event.as_str(),
+            session.id
+        );
+    }
+}
+
+/// Reports `event` of the current session to its observers, when running under
+/// [`Observers::observe`].
+pub(crate) fn notify(event: Event) {
+    let _ = EVENTS.try_with(|events| events.send(event));
+}
+
+/// Reports the end of a session, even when it is aborted
+struct Closed(UnboundedSender<Event>);
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        let _ = self.0.send(Event::Closed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::SessionKind;
+    use std::{sync::Mutex, time::Duration};
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+        closed: Mutex<Option<oneshot::Sender<()>>>,
+    }
+
+    #[async_trait]
+    impl SessionObserver for Recorder {
+        async fn on_connect(&self, session: &Session) {
+            self.events.lock().unwrap().push(session.target.clone());
+        }
+
+        async fn on_phase(&self, _session: &Session, phase: Phase) {
+            self.events.lock().unwrap().push(phase.as_str().to_string());
+        }
+
+        async fn on_failed(&self, _session: &Session, failure: &SessionFailure) {
+            self.events.lock().unwrap().push(failure.error.clone());
+        }
+
+        async fn on_closed(&self, _session: &Session) {
+            self.events.lock().unwrap().push("closed".into());
+            if let Some(closed) = self.closed.lock().unwrap().take() {
+                let _ = closed.send(());
+            }
+        }
+    }
+
+    struct Panicking;
+
+    #[async_trait]
+    impl SessionObserver for Panicking {
+        async fn on_phase(&self, _session: &Session, _phase: Phase) {
+            panic!("observer bug");
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_events_in_order_despite_panicking_observers() {
+        let (closed, on_closed) = oneshot::channel();
+        let recorder = Arc::new(Recorder {
+            closed: Mutex::new(Some(closed)),
+            ..Recorder::default()
+        });
+        let mut observers = Observers::default();
+        observers.push(Arc::new(Panicking));
+        observers.push(recorder.clone());
+        let session = Session {
+            id: Uuid::new_v4(),
+            kind: SessionKind::Verify,
+            target: "swissbank.tlsnotary.org".into(),
+            client_ip: None,
+            wallet: None,
+        };
+
+        let result = observers
+            .observe(session, async {
+                Phase::Setup.enter();
+                notify(Event::Failed(SessionFailure::new(&eyre::eyre!("no bank"))));
+                42
+            })
+            .await;
+        assert_eq!(result, 42);
+
+        tokio::time::timeout(Duration::from_secs(5), on_closed)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["swissbank.tlsnotary.org", "setup", "no bank", "closed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn queues_no_events_without_observers() {
+        let session = Session {
+            id: Uuid::new_v4(),
+            kind: SessionKind::Verify,
+            target: "swissbank.tlsnotary.org".into(),
+            client_ip: None,
+            wallet: None,
+        };
+
+        let queued = Observers::default()
+            .observe(session, async {
+                Phase::Setup.enter();
+                EVENTS.try_with(|_| ()).is_ok()
+            })
+            .await;
+        assert!(!queued);
+    }
+}
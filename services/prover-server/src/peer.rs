@@ -0,0 +1,353 @@
+//! Address of the client behind a connection.
+//!
+//! The peer of a connection is taken from the socket, or from a PROXY protocol v1/v2
+//! header when the server sits behind a TCP load balancer like HAProxy. Behind an HTTP
+//! reverse proxy, [`ClientIp`] then follows the `Forwarded` or `X-Forwarded-For` chain
+//! for as long as the hops are trusted proxies.
+
+use crate::SharedGlobals;
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use eyre::eyre;
+use http::{header::FORWARDED, HeaderMap};
+use ipnet::IpNet;
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Peer of a connection, `None` for Unix sockets. Added to the extensions of every request.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PeerAddr(pub Option<SocketAddr>);
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(address) => address.fmt(f),
+            None => f.write_str("Unix socket"),
+        }
+    }
+}
+
+/// IP address of the client, resolved through trusted proxies
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<SharedGlobals> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        globals: &SharedGlobals,
+    ) -> Result<Self, Self::Rejection> {
+        // Embedded in another app, the peer comes from `into_make_service_with_connect_info`.
+        // Without it, forwarded headers can't be trusted.
+        let peer = match parts.extensions.get::<PeerAddr>() {
+            Some(peer) => peer.0.map(|peer| peer.ip()),
+            None => match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+                Some(ConnectInfo(peer)) => Some(peer.ip()),
+                None => return Ok(Self(None)),
+            },
+        };
+        Ok(Self(client_ip(
+            peer,
+            &parts.headers,
+            &globals.current().trusted_proxies,
+        )))
+    }
+}
+
+/// Follows the forwarded chain from the peer, stopping at the first hop that isn't a
+/// trusted proxy. A Unix socket peer is a local reverse proxy and always trusted.
+pub(crate) fn client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if peer.as_ref().is_some_and(|peer| !is_trusted(peer)) {
+        return peer;
+    }
+
+    let mut client = peer;
+    // Proxies append to the chain, so the nearest hop comes last
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = Some(hop);
+        if !is_trusted(&hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Addresses of the `for` parameters of `Forwarded`, or of `X-Forwarded-For` when there is
+/// no `Forwarded` header. Obfuscated or unknown hops are `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value.trim_matches('"')))
+                })?
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `[2001:db8::1]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY";
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including the trailing CRLF
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+/// Reads the PROXY protocol header at the start of `stream`, returning the source address
+/// of the client. `None` for health checks of the proxy itself (`LOCAL` or `UNKNOWN`).
+pub(crate) async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, eyre::ErrReport> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == PROXY_V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == PROXY_V1_MAX_LENGTH {
+                return Err(eyre!("PROXY protocol v1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_proxy_v1(&line)
+    } else if prefix == PROXY_V2_SIGNATURE[..5] {
+        let mut header = [0u8; 16];
+        header[..5].copy_from_slice(&prefix);
+        stream.read_exact(&mut header[5..]).await?;
+        if &header[..12] != PROXY_V2_SIGNATURE {
+            return Err(eyre!("Invalid PROXY protocol v2 signature"));
+        }
+        let mut addresses = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_proxy_v2(&header, &addresses)
+    } else {
+        Err(eyre!(
+            "Connection doesn't start with a PROXY protocol header"
+        ))
+    }
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_proxy_v1(line: &[u8]) -> Result<Option<SocketAddr>, eyre::ErrReport> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| eyre!("PROXY protocol v1 header is not ASCII"))?
+        .trim_end_matches("\r\n");
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|err| eyre!("Invalid PROXY protocol source address {source}: {err}"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|err| eyre!("Invalid PROXY protocol source port {source_port}: {err}"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(eyre!("Malformed PROXY protocol v1 header {line:?}")),
+    }
+}
+
+fn parse_proxy_v2(
+    header: &[u8; 16],
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, eyre::ErrReport> {
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(eyre!("Unsupported PROXY protocol version {version}"));
+    }
+    match command {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(eyre!("Unsupported PROXY protocol v2 command {command}")),
+    }
+
+    let family = header[13] >> 4;
+    let source = match family {
+        // AF_INET: source, destination, source port, destination port
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        // AF_INET6
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?);
+            SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )
+        }
+        // AF_UNSPEC or AF_UNIX, no usable address
+        0x0 | 0x3 => return Ok(None),
+        _ => return Err(eyre!("Malformed PROXY protocol v2 addresses")),
+    };
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn resolves_client_through_trusted_proxies() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let forwarded = headers(&[("x-forwarded-for", "203.0.113.7, 198.51.100.1, 10.0.0.3")]);
+
+        // The nearest untrusted hop is the client, earlier entries could be spoofed
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &forwarded, &trusted),
+            ip("198.51.100.1")
+        );
+        // Headers from untrusted peers are ignored
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &forwarded, &trusted),
+            ip("192.0.2.1")
+        );
+        // Unix socket peers are local proxies
+        assert_eq!(client_ip(None, &forwarded, &trusted), ip("198.51.100.1"));
+        assert_eq!(client_ip(None, &HeaderMap::new(), &trusted), None);
+
+        let all_trusted = nets(&["0.0.0.0/0"]);
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &forwarded, &all_trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn prefers_forwarded_header() {
+        let trusted = nets(&["127.0.0.1/32"]);
+        let forwarded = headers(&[
+            ("x-forwarded-for", "192.0.2.99"),
+            (
+                "forwarded",
+                r#"for=192.0.2.60;proto=http, For="[2001:db8:cafe::17]:4711""#,
+            ),
+        ]);
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &forwarded, &trusted),
+            ip("2001:db8:cafe::17")
+        );
+
+        // An obfuscated hop stops the chain at the proxy that added it
+        let forwarded = headers(&[("forwarded", "for=192.0.2.60, for=_hidden")]);
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &forwarded, &trusted),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_proxy_v1_header() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        // The request that follows is left in the stream
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_proxy_header(&mut stream).await.is_err());
+
+        let long = [b"PROXY ".as_slice(), &[b'1'; 200]].concat();
+        assert!(read_proxy_header(&mut long.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_proxy_v2_header() {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        // Version 2, PROXY, AF_INET over TCP, 12 bytes of addresses
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"GET");
+
+        let mut stream = header.as_slice();
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+
+        // LOCAL, sent by health checks
+        let mut local = PROXY_V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut local.as_slice()).await.unwrap(),
+            None
+        );
+    }
+}
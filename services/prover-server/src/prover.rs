@@ -1,193 +1,218 @@
+use eyre::eyre;
 use http::header;
 use http_body_util::Empty;
 use hyper::{body::Bytes, Request, StatusCode, Uri};
 use hyper_util::rt::TokioIo;
-use rangeset::RangeSet;
-use spansy::{
-    http::{parse_response, Requests},
-    json::{self},
-    Spanned,
-};
 
-use crate::config::{MAX_RECV_DATA, MAX_SENT_DATA};
-use tlsn::config::ProtocolConfig;
+use crate::config::{NetworkMode, ProtocolTuning, RedactionPolicy, SessionTimeouts};
+use crate::redaction;
+use crate::sessions::OwnedTask;
+use crate::telemetry::Phase;
+use crate::timeouts::{within, Deadline};
+use crate::tls::TargetRoots;
+use crate::verifier::root_store;
+use tlsn::config::{NetworkSetting, ProtocolConfig};
 use tlsn::connection::ServerName;
-use tlsn::prover::{ProveConfig, ProveConfigBuilder, Prover, ProverConfig};
+use tlsn::prover::{
+    state::Committed, ProveConfig, ProveConfigBuilder, Prover, ProverConfig, TlsConfig,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
-use tracing::{debug, info};
+use tracing::{debug, info, Instrument};
 
 pub async fn prover<T: AsyncWrite + AsyncRead + Send + Unpin + 'static>(
     verifier_socket: T,
     server_uri: &Uri,
+    roots: &TargetRoots,
+    policy: &RedactionPolicy,
+    tuning: &ProtocolTuning,
+    timeouts: &SessionTimeouts,
 ) -> Result<(), eyre::ErrReport> {
     debug!("Starting proving...");
 
-    assert_eq!(server_uri.scheme().unwrap().as_str(), "https");
-    let server_domain = server_uri.authority().unwrap().host();
+    if server_uri.scheme_str() != Some("https") {
+        return Err(eyre!("Server URI {server_uri} must use https"));
+    }
+    let server_domain = server_uri
+        .host()
+        .ok_or_else(|| eyre!("Server URI {server_uri} has no host"))?;
     let server_port = server_uri.port_u16().unwrap_or(443);
 
     // Create prover and connect to verifier.
     let prover_config = ProverConfig::builder()
-        .server_name(ServerName::Dns(server_domain.try_into().unwrap()))
-        .protocol_config(
-            ProtocolConfig::builder()
-                .max_sent_data(MAX_SENT_DATA)
-                .max_recv_data(MAX_RECV_DATA)
+        .server_name(ServerName::Dns(server_domain.try_into().map_err(
+            |err| eyre!("Invalid server name {server_domain}: {err}"),
+        )?))
+        .tls_config(
+            TlsConfig::builder()
+                .root_store(root_store(roots))
                 .build()
-                .unwrap(),
+                .map_err(|err| eyre!("Invalid TLS config: {err}"))?,
         )
+        .protocol_config(protocol_config(tuning)?)
         .build()
-        .unwrap();
+        .map_err(|err| eyre!("Invalid prover config: {err}"))?;
 
     // Perform the setup phase with the verifier.
-    let prover = Prover::new(prover_config)
-        .setup(verifier_socket.compat())
-        .await
-        .unwrap();
-
-    // Connect to TLS Server.
-    let tls_client_socket = tokio::net::TcpStream::connect((server_domain, server_port))
-        .await
-        .unwrap();
-
-    // Pass server connection into the prover.
-    let (mpc_tls_connection, prover_fut) =
-        prover.connect(tls_client_socket.compat()).await.unwrap();
-    let mpc_tls_connection = TokioIo::new(mpc_tls_connection.compat());
-
-    // Spawn the prover task to be run concurrently in the background.
-    let prover_task = tokio::spawn(prover_fut);
-
-    // MPC-TLS Handshake.
-    let (mut request_sender, connection) =
-        hyper::client::conn::http1::handshake(mpc_tls_connection)
-            .await
-            .unwrap();
+    let prover = within(
+        Deadline::Setup,
+        timeouts.get(Deadline::Setup),
+        Prover::new(prover_config)
+            .setup(verifier_socket.compat())
+            .instrument(Phase::Setup.span()),
+    )
+    .await?
+    .map_err(|err| eyre!("Prover setup failed: {err}"))?;
+
+    // Connect to TLS Server and perform the MPC-TLS Handshake.
+    let (mut request_sender, connection, prover_fut) =
+        within(Deadline::Connect, timeouts.get(Deadline::Connect), async {
+            let tls_client_socket = tokio::net::TcpStream::connect((server_domain, server_port))
+                .instrument(Phase::Connect.span())
+                .await
+                .map_err(|err| {
+                    eyre!("Failed to connect to {server_domain}:{server_port}: {err}")
+                })?;
+
+            // Pass server connection into the prover.
+            let handshake_span = Phase::Handshake.span();
+            let (mpc_tls_connection, prover_fut) = prover
+                .connect(tls_client_socket.compat())
+                .instrument(handshake_span.clone())
+                .await
+                .map_err(|err| eyre!("MPC-TLS handshake failed: {err}"))?;
+            let mpc_tls_connection = TokioIo::new(mpc_tls_connection.compat());
+
+            let (request_sender, connection) =
+                hyper::client::conn::http1::handshake(mpc_tls_connection)
+                    .instrument(handshake_span)
+                    .await
+                    .map_err(|err| eyre!("HTTP handshake with the server failed: {err}"))?;
+            Ok::<_, eyre::ErrReport>((request_sender, connection, prover_fut))
+        })
+        .await??;
 
-    tokio::spawn(connection);
+    // The MPC-TLS connection runs in the background until the response is received. Both
+    // tasks are aborted when this future is dropped, closing the connection to the server.
+    let request_span = Phase::Request.span();
+    let prover_task = OwnedTask::spawn(prover_fut.instrument(request_span.clone()));
+    let _connection = OwnedTask::spawn(connection.instrument(request_span.clone()));
 
     // MPC-TLS: Send Request and wait for Response.
-    info!("Send Request and wait for Response");
-    let request = Request::builder()
-        .uri(server_uri.clone())
-        .header("Host", server_domain)
-        .header("Connection", "close")
-        .header(header::AUTHORIZATION, "Bearer random_auth_token")
-        .method("GET")
-        .body(Empty::<Bytes>::new())
-        .unwrap();
-    let response = request_sender.send_request(request).await.unwrap();
-
-    debug!("TLS response: {:?}", response);
-    assert!(response.status() == StatusCode::OK);
+    let response = async {
+        info!("Send Request and wait for Response");
+        let request = Request::builder()
+            .uri(server_uri.clone())
+            .header("Host", server_domain)
+            .header("Connection", "close")
+            .header(header::AUTHORIZATION, "Bearer random_auth_token")
+            .method("GET")
+            .body(Empty::<Bytes>::new())
+            .map_err(|err| eyre!("Invalid request: {err}"))?;
+        let response = request_sender
+            .send_request(request)
+            .await
+            .map_err(|err| eyre!("Request to the server failed: {err}"))?;
+
+        debug!("TLS response: {:?}", response);
+        if response.status() != StatusCode::OK {
+            return Err(eyre!("Server responded with {}", response.status()));
+        }
+
+        prover_task
+            .await
+            .map_err(|err| eyre!("Prover task failed: {err}"))?
+            .map_err(|err| eyre!("MPC-TLS connection failed: {err}"))
+    }
+    .instrument(request_span);
+    let mut prover = within(
+        Deadline::Response,
+        timeouts.get(Deadline::Response),
+        response,
+    )
+    .await??;
 
     // Create proof for the Verifier.
-    let mut prover = prover_task.await.unwrap().unwrap();
+    within(
+        Deadline::Finalize,
+        timeouts.get(Deadline::Finalize),
+        async {
+            let prove_span = Phase::Prove.span();
+            let config = prove_span.in_scope(|| prove_config(&prover, policy))?;
+            prover
+                .prove(&config)
+                .instrument(prove_span.clone())
+                .await
+                .map_err(|err| eyre!("Proving failed: {err}"))?;
+            // `handle_socket` enters the close phase once the session ended, however it ended
+            prover
+                .close()
+                .instrument(prove_span)
+                .await
+                .map_err(|err| eyre!("Failed to close proving session: {err}"))?;
+            Ok::<_, eyre::ErrReport>(())
+        },
+    )
+    .await?
+}
 
-    info!(
-        "server signature: {:?}",
-        prover.tls_transcript().server_signature().unwrap().alg,
-    );
+/// Reveals the server name and the parts of the transcript allowed by `policy`.
+fn prove_config(
+    prover: &Prover<Committed>,
+    policy: &RedactionPolicy,
+) -> Result<ProveConfig, eyre::ErrReport> {
+    if let Some(signature) = prover.tls_transcript().server_signature() {
+        info!("server signature: {:?}", signature.alg);
+    }
 
     let mut builder: ProveConfigBuilder<'_> = ProveConfig::builder(prover.transcript());
 
     // Reveal the DNS name.
     builder.server_identity();
 
-    let sent_rangeset = redact_and_reveal_sent_data(prover.transcript().sent());
-    let _ = builder.reveal_sent(&sent_rangeset);
-
-    let recv_rangeset = redact_and_reveal_received_data(prover.transcript().received());
-    let _ = builder.reveal_recv(&recv_rangeset);
-
-    let config = builder.build().unwrap();
-
-    prover.prove(&config).await.unwrap();
-    prover.close().await.unwrap();
-
-    Ok(())
-}
-
-/// Redacts and reveals received data to the verifier.
-fn redact_and_reveal_received_data(recv_transcript: &[u8]) -> RangeSet<usize> {
-    // Parse and log the received data
-    if let Ok(received_string) = std::str::from_utf8(recv_transcript) {
-        debug!("Received data: {}", received_string);
+    let reveal = redaction::reveal(
+        policy,
+        prover.transcript().sent(),
+        prover.transcript().received(),
+    )?;
+    if !reveal.unresolved.is_empty() {
+        return Err(eyre!(
+            "Fields not found in the response: {}",
+            reveal.unresolved.join(", ")
+        ));
     }
-
-    // Parse response and extract JSON body
-    let resp = parse_response(recv_transcript).expect("Failed to parse HTTP response");
-    let body = resp.body.expect("Response body not found");
-    let mut json = json::parse_slice(body.as_bytes()).expect("Failed to parse JSON");
-
-    let body_offset = body
-        .content
-        .span()
-        .indices()
-        .min()
-        .expect("Failed to get body content offset");
-    json.offset(body_offset);
-
-    // Extract required JSON fields from SwissBank format
-    let fields = [
-        ("organization", "\"organization\": \""),
-        ("bank", "\"bank\": \""),
-        ("accounts.USD", "\"USD\": \""),
-        ("accounts.EUR", "\"EUR\": \""),
-        ("accounts.CHF", "\"CHF\": \""),
-    ];
-
-    // Create ranges for each field
-    let ranges: Vec<_> = fields
-        .iter()
-        .map(|(path, prefix)| {
-            let field = json
-                .get(path)
-                .unwrap_or_else(|| panic!("{} field not found", path));
-
-            let span_indices = field.span().indices();
-            let start = span_indices
-                .min()
-                .expect("Failed to get field start position")
-                - prefix.len();
-            let end = span_indices
-                .max()
-                .expect("Failed to get field end position")
-                + 2; // include closing quote and next char
-
-            start..end
-        })
-        .collect();
-
-    ranges.into()
+    builder
+        .reveal_sent(&reveal.sent)
+        .map_err(|err| eyre!("Failed to reveal sent data: {err}"))?;
+    builder
+        .reveal_recv(&reveal.recv)
+        .map_err(|err| eyre!("Failed to reveal received data: {err}"))?;
+
+    builder
+        .build()
+        .map_err(|err| eyre!("Failed to build prove config: {err}"))
 }
 
-/// Redacts and reveals sent data to the verifier.
-fn redact_and_reveal_sent_data(sent_transcript: &[u8]) -> RangeSet<usize> {
-    let sent_transcript_len = sent_transcript.len();
-
-    let reqs = Requests::new_from_slice(sent_transcript)
-        .collect::<Result<Vec<_>, _>>()
-        .unwrap();
-
-    let req = reqs.first().ok_or("No requests found").unwrap();
-
-    let authorization_header = req
-        .headers_with_name(header::AUTHORIZATION.as_str())
-        .next()
-        .expect("Authorization header not found");
-
-    let start_pos = authorization_header
-        .span()
-        .indices()
-        .min()
-        .expect("Could not find authorization header start position")
-        + header::AUTHORIZATION.as_str().len()
-        + 2;
-    let end_pos =
-        start_pos + authorization_header.span().len() - header::AUTHORIZATION.as_str().len() - 2;
-
-    // Reveal everything except for the SECRET.
-    [0..start_pos, end_pos..sent_transcript_len].into()
+fn protocol_config(tuning: &ProtocolTuning) -> Result<ProtocolConfig, eyre::ErrReport> {
+    let mut builder = ProtocolConfig::builder();
+    builder
+        .max_sent_data(tuning.max_sent_data)
+        .max_recv_data(tuning.max_recv_data)
+        .defer_decryption_from_start(tuning.defer_decryption)
+        .network(match tuning.network {
+            NetworkMode::Latency => NetworkSetting::Latency,
+            NetworkMode::Bandwidth => NetworkSetting::Bandwidth,
+        });
+    if let Some(max_recv_data_online) = tuning.max_recv_data_online {
+        builder.max_recv_data_online(max_recv_data_online);
+    }
+    if let Some(max_sent_records) = tuning.max_sent_records {
+        builder.max_sent_records(max_sent_records);
+    }
+    if let Some(max_recv_records_online) = tuning.max_recv_records_online {
+        builder.max_recv_records_online(max_recv_records_online);
+    }
+    builder
+        .build()
+        .map_err(|err| eyre!("Invalid protocol config: {err}"))
 }
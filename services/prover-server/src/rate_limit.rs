@@ -0,0 +1,144 @@
+//! Fixed-window limit on the sessions a client IP can open.
+//!
+//! IPv6 clients are limited per /64, the smallest prefix usually assigned to a subscriber,
+//! so rotating addresses within it doesn't reset the limit.
+
+use crate::config::RateLimitConfig;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Windows are pruned once this many clients are tracked
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Shortest time between two prunes, so a full map isn't scanned on every session
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients tracked at most; new clients are refused until their windows fit again
+const MAX_CLIENTS: usize = 100_000;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_sessions: u32,
+    window: Duration,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    /// Start and session count of the current window of each client
+    clients: HashMap<IpAddr, (Instant, u32)>,
+    pruned_at: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            max_sessions: config.max_sessions,
+            window: Duration::from_secs(config.window_secs),
+            windows: Mutex::new(Windows::default()),
+        }
+    }
+
+    /// Counts a session for `client`, returning whether it is within the limit.
+    pub fn check(&self, client: IpAddr) -> bool {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let Windows { clients, pruned_at } = &mut *windows;
+        let prune_due = pruned_at
+            .is_none_or(|pruned_at| now.saturating_duration_since(pruned_at) >= PRUNE_INTERVAL);
+        if clients.len() >= PRUNE_THRESHOLD && prune_due {
+            clients.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+            *pruned_at = Some(now);
+        }
+
+        let client = client_key(client);
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            return false;
+        }
+        let (started, sessions) = clients.entry(client).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            (*started, *sessions) = (now, 0);
+        }
+        if *sessions >= self.max_sessions {
+            return false;
+        }
+        *sessions += 1;
+        true
+    }
+}
+
+/// Address whose window counts the sessions of `client`: the /64 network of an IPv6 client.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from(u128::from(address) & !0 << 64)),
+        ipv4 => ipv4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_sessions_per_window() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            max_sessions: 2,
+            window_secs: 60,
+        });
+        let (alice, bob) = ("203.0.113.7".parse().unwrap(), "::1".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at(alice, start));
+        assert!(limiter.check_at(alice, start));
+        assert!(!limiter.check_at(alice, start + Duration::from_secs(59)));
+        assert!(limiter.check_at(bob, start));
+
+        assert!(limiter.check_at(alice, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn limits_ipv6_clients_per_64() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            max_sessions: 2,
+            window_secs: 60,
+        });
+        let start = Instant::now();
+
+        assert!(limiter.check_at("2001:db8:0:1::1".parse().unwrap(), start));
+        assert!(limiter.check_at("2001:db8:0:1:ffff::2".parse().unwrap(), start));
+        assert!(!limiter.check_at("2001:db8:0:1::3".parse().unwrap(), start));
+        assert!(limiter.check_at("2001:db8:0:2::1".parse().unwrap(), start));
+        // IPv4-mapped addresses count as their IPv4 client
+        assert!(limiter.check_at("::ffff:203.0.113.7".parse().unwrap(), start));
+        assert!(limiter.check_at("203.0.113.7".parse().unwrap(), start));
+        assert!(!limiter.check_at("203.0.113.7".parse().unwrap(), start));
+    }
+
+    #[test]
+    fn refuses_new_clients_beyond_the_cap() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            max_sessions: 2,
+            window_secs: 60,
+        });
+        let start = Instant::now();
+        let client = |index: usize| IpAddr::from((index as u32).to_be_bytes());
+
+        for index in 0..MAX_CLIENTS {
+            assert!(limiter.check_at(client(index), start));
+        }
+        assert!(!limiter.check_at(client(MAX_CLIENTS), start));
+        assert!(limiter.check_at(client(0), start));
+
+        // Expired windows make room again
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check_at(client(MAX_CLIENTS), later));
+        assert_eq!(limiter.windows.lock().unwrap().clients.len(), 1);
+    }
+}
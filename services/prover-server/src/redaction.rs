@@ -0,0 +1,965 @@
+//! Applies a [`RedactionPolicy`] to a transcript, computing the byte ranges the prover
+//! reveals to the verifier.
+//!
+//! JSON fields are located with spansy's spans, so any value kind is supported. A path is a
+//! dot-separated list of keys with optional array indices, where `*` matches every key or
+//! element: `organization`, `accounts[0].balance`, `accounts.*`, `items[*].id`.
+//!
+//! Regex rules reveal their matches in the body or in header lines of any response. Their
+//! named capture groups are read back by the verifier with [`extract_claims`].
+//!
+//! The status line and chosen headers can be revealed too, which the verifier checks with
+//! [`check_response_head`]. Header values are revealed whole or not at all, and the names
+//! and line breaks of every header are revealed along with them, so the verifier can tell
+//! the head from the body using authenticated bytes only.
+
+use crate::{
+    config::{RedactionPolicy, RegexRule, RegexScope},
+    transcript::render,
+};
+use eyre::eyre;
+use rangeset::RangeSet;
+use regex::bytes::Regex;
+use serde::Serialize;
+use spansy::{
+    http::{parse_response, Requests},
+    json::{self, JsonValue, KeyValue},
+    Spanned,
+};
+use std::{collections::BTreeMap, ops::Range};
+use tracing::debug;
+
+/// Transcript ranges revealed under a policy
+#[derive(Clone, Debug)]
+pub struct Reveal {
+    pub sent: RangeSet<usize>,
+    pub recv: RangeSet<usize>,
+    /// Policy entries that matched nothing in the transcript
+    pub unresolved: Vec<String>,
+}
+
+/// Computes the revealed ranges of the sent and received data.
+pub fn reveal(
+    policy: &RedactionPolicy,
+    sent: &[u8],
+    received: &[u8],
+) -> Result<Reveal, eyre::ErrReport> {
+    let mut unresolved = Vec::new();
+    let sent = reveal_sent(policy, sent)?;
+    let recv = reveal_received(policy, received, &mut unresolved)?;
+
+    Ok(Reveal {
+        sent,
+        recv,
+        unresolved,
+    })
+}
+
+/// Outcome of applying a policy to a sample transcript, without running MPC-TLS
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyPreview {
+    pub sent: Vec<Range<usize>>,
+    pub received: Vec<Range<usize>>,
+    /// Sent data as the verifier would see it, with hidden bytes rendered as `🙈`
+    pub sent_redacted: String,
+    /// Received data as the verifier would see it, with hidden bytes rendered as `🙈`
+    pub received_redacted: String,
+    pub unresolved: Vec<String>,
+}
+
+/// Dry run of a policy against a raw HTTP request and response.
+pub fn preview(
+    policy: &RedactionPolicy,
+    sent: &[u8],
+    received: &[u8],
+) -> Result<PolicyPreview, eyre::ErrReport> {
+    let reveal = reveal(policy, sent, received)?;
+    Ok(PolicyPreview {
+        sent: reveal.sent.iter_ranges().collect(),
+        received: reveal.recv.iter_ranges().collect(),
+        sent_redacted: render(sent, &reveal.sent),
+        received_redacted: render(received, &reveal.recv),
+        unresolved: reveal.unresolved,
+    })
+}
+
+/// Replaces the bytes outside of `revealed` with `\0`, as the verifier sees them.
+pub fn redact(data: &[u8], revealed: &RangeSet<usize>) -> Vec<u8> {
+    let mut redacted = vec![0; data.len()];
+    for range in revealed.iter_ranges() {
+        let range = range.start.min(data.len())..range.end.min(data.len());
+        redacted[range.clone()].copy_from_slice(&data[range]);
+    }
+    redacted
+}
+
+/// Reveals the requests, except for the values of the redacted headers.
+fn reveal_sent(policy: &RedactionPolicy, sent: &[u8]) -> Result<RangeSet<usize>, eyre::ErrReport> {
+    let requests = Requests::new_from_slice(sent)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| eyre!("Failed to parse sent HTTP requests: {err}"))?;
+
+    let mut hidden: Vec<Range<usize>> = requests
+        .iter()
+        .flat_map(|request| {
+            policy
+                .redact_request_headers
+                .iter()
+                .flat_map(move |name| request.headers_with_name(name.as_str()))
+        })
+        .filter_map(|header| span_range(header.value.span().indices()))
+        .collect();
+    hidden.sort_by_key(|range| range.start);
+
+    let mut revealed = Vec::new();
+    let mut start = 0;
+    for range in hidden {
+        if range.start > start {
+            revealed.push(start..range.start);
+        }
+        start = start.max(range.end);
+    }
+    revealed.push(start..sent.len());
+
+    Ok(revealed.into())
+}
+
+/// Reveals the response fields selected by the policy.
+fn reveal_received(
+    policy: &RedactionPolicy,
+    received: &[u8],
+    unresolved: &mut Vec<String>,
+) -> Result<RangeSet<usize>, eyre::ErrReport> {
+    if let Ok(received_string) = std::str::from_utf8(received) {
+        debug!("Received data: {}", received_string);
+    }
+
+    let response =
+        parse_response(received).map_err(|err| eyre!("Failed to parse HTTP response: {err}"))?;
+    let mut ranges = Vec::new();
+
+    if !policy.reveal_json.is_empty() {
+        let body = response
+            .body
+            .as_ref()
+            .and_then(|body| Some((body, json::parse_slice(body.as_bytes()).ok()?)));
+        match body {
+            Some((body, mut json)) => {
+                let body_offset = body
+                    .content
+                    .span()
+                    .indices()
+                    .min()
+                    .ok_or_else(|| eyre!("Response body is empty"))?;
+                json.offset(body_offset);
+
+                for path in &policy.reveal_json {
+                    let segments = parse_path(path)?;
+                    if !resolve(&json, &segments, received, &mut ranges) {
+                        unresolved.push(path.clone());
+                    }
+                }
+            }
+            None => {
+                debug!("Response body is not JSON");
+                unresolved.extend(policy.reveal_json.iter().cloned());
+            }
+        }
+    }
+
+    let revealed_headers = revealed_headers(policy);
+    for name in &revealed_headers {
+        let mut headers = response.headers_with_name(name).peekable();
+        if headers.peek().is_none() {
+            unresolved.push(name.clone());
+        }
+        ranges.extend(headers.filter_map(|header| span_range(header.span().indices())));
+    }
+
+    if !policy.reveals_status_line()
+        && revealed_headers.is_empty()
+        && policy.reveal_regex.is_empty()
+    {
+        return Ok(ranges.into());
+    }
+
+    let parts =
+        ResponseParts::split(received).ok_or_else(|| eyre!("Response has no end of headers"))?;
+    // The verifier needs the status line, line breaks and header names to tell the body and
+    // header lines apart. Line breaks give nothing away, since hidden bytes keep their
+    // positions.
+    ranges.push(parts.status.clone());
+    ranges.extend(parts.line_breaks.iter().cloned());
+    ranges.extend(parts.names.iter().cloned());
+
+    for rule in &policy.reveal_regex {
+        let regex = compile(rule)?;
+        let mut matched = false;
+        for scope in parts.scope(rule.scope) {
+            for found in regex.find_iter(&received[scope.clone()]) {
+                ranges.push(match rule.scope {
+                    // Header values are revealed whole
+                    RegexScope::Headers => scope.clone(),
+                    // One more byte on each side shows the verifier where the match ends
+                    RegexScope::Body => {
+                        let start = (scope.start + found.start()).saturating_sub(1);
+                        let end = (scope.start + found.end() + 1).min(received.len());
+                        start..end
+                    }
+                });
+                matched = true;
+            }
+        }
+        if !matched {
+            unresolved.push(rule.pattern.clone());
+        }
+    }
+
+    Ok(ranges.into())
+}
+
+/// Reads the named capture groups of the policy's regex rules from received data, of which
+/// only the `authed` ranges are authenticated. Every rule has to match, and its matches have
+/// to be authenticated. So do the bytes around a match, or hiding them could cut a claim
+/// short, like `100` out of `1000`. The first match of a group wins.
+pub fn extract_claims(
+    policy: &RedactionPolicy,
+    received: &[u8],
+    authed: &RangeSet<usize>,
+) -> Result<BTreeMap<String, String>, eyre::ErrReport> {
+    let mut claims = BTreeMap::new();
+    if policy.reveal_regex.is_empty() {
+        return Ok(claims);
+    }
+
+    let parts = ResponseParts::authenticated(received, authed)?;
+    for rule in &policy.reveal_regex {
+        let regex = compile(rule)?;
+        let mut matched = false;
+        for scope in parts.scope(rule.scope) {
+            for captures in regex.captures_iter(&received[scope.clone()]) {
+                matched = true;
+                let found = captures.get(0).expect("group 0 is the whole match");
+                let start = (scope.start + found.start()).saturating_sub(1);
+                let end = (scope.start + found.end() + 1).min(received.len());
+                if !is_authenticated(authed, &(start..end)) {
+                    return Err(eyre!(
+                        "Match of {:?} is not authenticated along with the bytes around it",
+                        rule.pattern
+                    ));
+                }
+                for name in regex.capture_names().flatten() {
+                    let Some(capture) = captures.name(name) else {
+                        continue;
+                    };
+                    let value = std::str::from_utf8(capture.as_bytes())
+                        .map_err(|err| eyre!("Claim field {name} is not UTF-8: {err}"))?;
+                    claims
+                        .entry(name.to_string())
+                        .or_insert_with(|| value.to_string());
+                }
+            }
+        }
+        if !matched {
+            return Err(eyre!("No revealed data matches {:?}", rule.pattern));
+        }
+    }
+
+    Ok(claims)
+}
+
+/// Checks the revealed status line and headers: the status has to be 200, and the content
+/// type has to match the policy's `expected_content_type`. The head is located in the ranges
+/// of `received` that are `authed`, see [`ResponseParts::authenticated`], and its revealed
+/// lines are parsed with spansy like a complete response.
+pub fn check_response_head(
+    policy: &RedactionPolicy,
+    received: &[u8],
+    authed: &RangeSet<usize>,
+) -> Result<(), eyre::ErrReport> {
+    if !policy.reveals_status_line() {
+        return Ok(());
+    }
+
+    let parts = ResponseParts::authenticated(received, authed)?;
+    let mut head = received[parts.status.clone()].to_vec();
+    head.extend_from_slice(b"\r\n");
+    for line in &parts.headers {
+        if !is_authenticated(authed, line) {
+            continue;
+        }
+        let line = &received[line.clone()];
+        // Without a body, framing headers would make the head unparsable
+        let framing = [b"content-length:".as_slice(), b"transfer-encoding:"]
+            .iter()
+            .any(|name| line.len() >= name.len() && line[..name.len()].eq_ignore_ascii_case(name));
+        if !framing {
+            head.extend_from_slice(line);
+            head.extend_from_slice(b"\r\n");
+        }
+    }
+    head.extend_from_slice(b"\r\n");
+
+    let response =
+        parse_response(&head).map_err(|err| eyre!("Failed to parse response head: {err}"))?;
+
+    let code = response.status.code.as_str();
+    if code != "200" {
+        return Err(eyre!("Unexpected response status {code}"));
+    }
+
+    if let Some(expected) = &policy.expected_content_type {
+        let content_type = response
+            .headers_with_name("content-type")
+            .next()
+            .ok_or_else(|| eyre!("Content-Type header is not revealed"))?;
+        let content_type = std::str::from_utf8(content_type.value.as_bytes())
+            .map_err(|err| eyre!("Content-Type is not UTF-8: {err}"))?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case(expected) {
+            return Err(eyre!(
+                "Unexpected content type {content_type}, expected {expected}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the authenticated part of the received data contains every entry of the
+/// policy's `required_content`.
+pub fn check_required_content(
+    policy: &RedactionPolicy,
+    received: &[u8],
+    authed: &RangeSet<usize>,
+) -> Result<(), eyre::ErrReport> {
+    for required in &policy.required_content {
+        let needle = required.as_bytes();
+        let found = needle.is_empty()
+            || authed.iter_ranges().any(|range| {
+                received[range]
+                    .windows(needle.len())
+                    .any(|window| window == needle)
+            });
+        if !found {
+            return Err(eyre!("Revealed data doesn't contain {required:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Response headers the prover reveals, including those the verifier checks.
+fn revealed_headers(policy: &RedactionPolicy) -> Vec<String> {
+    let mut headers = policy.reveal_response_headers.clone();
+    if policy.expected_content_type.is_some()
+        && !headers
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("content-type"))
+    {
+        headers.push("content-type".to_string());
+    }
+    headers
+}
+
+impl RedactionPolicy {
+    fn reveals_status_line(&self) -> bool {
+        self.reveal_status_line || self.expected_content_type.is_some()
+    }
+}
+
+fn compile(rule: &RegexRule) -> Result<Regex, eyre::ErrReport> {
+    Regex::new(&rule.pattern)
+        .map_err(|err| eyre!("Invalid reveal pattern {:?}: {err}", rule.pattern))
+}
+
+const HEADERS_END: &[u8] = b"\r\n\r\n";
+
+/// Header lines and body of a response, located by the first empty line
+struct ResponseParts {
+    /// Status line without its line break
+    status: Range<usize>,
+    /// Header lines without the status line and line breaks
+    headers: Vec<Range<usize>>,
+    /// Header names with their colon
+    names: Vec<Range<usize>>,
+    /// Line breaks of the status line and headers, and the empty line ending them
+    line_breaks: Vec<Range<usize>>,
+    body: Range<usize>,
+}
+
+impl ResponseParts {
+    fn split(response: &[u8]) -> Option<Self> {
+        let headers_end = response
+            .windows(HEADERS_END.len())
+            .position(|window| window == HEADERS_END)?;
+
+        let mut status = 0..0;
+        let mut headers = Vec::new();
+        let mut names = Vec::new();
+        let mut line_breaks = Vec::new();
+        let mut start = 0;
+        for line in response[..headers_end].split(|byte| *byte == b'\n') {
+            let end = start + line.len();
+            let content_end = end - usize::from(line.ends_with(b"\r"));
+            if start == 0 {
+                status = start..content_end;
+            } else {
+                headers.push(start..content_end);
+                names.extend(
+                    line.iter()
+                        .position(|byte| *byte == b':')
+                        .map(|colon| start..start + colon + 1),
+                );
+            }
+            line_breaks.push(content_end..end + 1);
+            start = end + 1;
+        }
+        // The break after the last header belongs to the end of headers
+        line_breaks.pop();
+        line_breaks.push(headers_end..headers_end + HEADERS_END.len());
+
+        Some(Self {
+            status,
+            headers,
+            names,
+            line_breaks,
+            body: headers_end + HEADERS_END.len()..response.len(),
+        })
+    }
+
+    /// Splits a response as the verifier sees it, using only the `authed` bytes. Lines end at
+    /// authenticated line breaks, the status line and header names have to be authenticated,
+    /// and header values fully authenticated or fully hidden. That way hiding the end of the
+    /// headers can't pass body lines off as headers, and real `\0` bytes aren't mistaken for
+    /// hidden ones.
+    fn authenticated(received: &[u8], authed: &RangeSet<usize>) -> Result<Self, eyre::ErrReport> {
+        let line_ends = (0..received.len().saturating_sub(1)).filter(|&index| {
+            &received[index..index + 2] == b"\r\n" && is_authenticated(authed, &(index..index + 2))
+        });
+
+        let mut status = None;
+        let mut headers = Vec::new();
+        let mut names = Vec::new();
+        let mut line_breaks = Vec::new();
+        let mut start = 0;
+        for end in line_ends {
+            let line = start..end;
+            line_breaks.push(end..end + 2);
+            start = end + 2;
+
+            let Some(status) = &status else {
+                if !is_authenticated(authed, &line) {
+                    return Err(eyre!("Status line is not revealed"));
+                }
+                status = Some(line);
+                continue;
+            };
+            if line.is_empty() {
+                return Ok(Self {
+                    status: status.clone(),
+                    headers,
+                    names,
+                    line_breaks,
+                    body: start..received.len(),
+                });
+            }
+
+            let name = received[line.clone()]
+                .iter()
+                .position(|byte| *byte == b':')
+                .map(|colon| line.start..line.start + colon + 1)
+                .filter(|name| is_authenticated(authed, name))
+                .ok_or_else(|| {
+                    eyre!("Name of the header at byte {} is not revealed", line.start)
+                })?;
+            let value = name.end..line.end;
+            if !is_authenticated(authed, &value) && !is_hidden(authed, &value) {
+                return Err(eyre!(
+                    "Value of the {} header is partially revealed",
+                    String::from_utf8_lossy(&received[name.start..name.end - 1])
+                ));
+            }
+            names.push(name);
+            headers.push(line);
+        }
+
+        Err(eyre!("End of response headers is not revealed"))
+    }
+
+    fn scope(&self, scope: RegexScope) -> Vec<Range<usize>> {
+        match scope {
+            RegexScope::Body => vec![self.body.clone()],
+            RegexScope::Headers => self.headers.clone(),
+        }
+    }
+}
+
+/// Whether every byte of `range` is in `authed`.
+fn is_authenticated(authed: &RangeSet<usize>, range: &Range<usize>) -> bool {
+    range.is_empty()
+        || authed
+            .iter_ranges()
+            .any(|authed| authed.start <= range.start && range.end <= authed.end)
+}
+
+/// Whether no byte of `range` is in `authed`.
+fn is_hidden(authed: &RangeSet<usize>, range: &Range<usize>) -> bool {
+    authed
+        .iter_ranges()
+        .all(|authed| authed.end <= range.start || range.end <= authed.start)
+}
+
+/// Step of a JSON path
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// Every key of an object or element of an array
+    Any,
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, eyre::ErrReport> {
+    let invalid = || eyre!("Invalid JSON path {path:?}");
+
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, mut indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+        match key {
+            "" if indices.is_empty() => return Err(invalid()),
+            "" => {}
+            "*" => segments.push(Segment::Any),
+            key => segments.push(Segment::Key(key.to_string())),
+        }
+        while !indices.is_empty() {
+            let end = indices.find(']').ok_or_else(invalid)?;
+            segments.push(match &indices[1..end] {
+                "*" => Segment::Any,
+                index => Segment::Index(index.parse().map_err(|_| invalid())?),
+            });
+            indices = &indices[end + 1..];
+            if !indices.is_empty() && !indices.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    if segments.is_empty() || path.starts_with('[') {
+        return Err(invalid());
+    }
+
+    Ok(segments)
+}
+
+/// Collects the ranges of everything `path` matches in `value`. Object members are revealed
+/// with their key, array elements on their own. Returns whether anything matched.
+fn resolve(
+    value: &JsonValue,
+    path: &[Segment],
+    src: &[u8],
+    ranges: &mut Vec<Range<usize>>,
+) -> bool {
+    let Some((segment, rest)) = path.split_first() else {
+        return false;
+    };
+
+    let mut found = false;
+    match value {
+        JsonValue::Object(object) => {
+            for member in &object.elems {
+                let matches = match segment {
+                    Segment::Key(key) => key_name(member, src) == key.as_str(),
+                    Segment::Any => true,
+                    Segment::Index(_) => false,
+                };
+                if !matches {
+                    continue;
+                }
+                if rest.is_empty() {
+                    if let Some(range) = member_range(member, src) {
+                        ranges.push(range);
+                        found = true;
+                    }
+                } else {
+                    found |= resolve(&member.value, rest, src, ranges);
+                }
+            }
+        }
+        JsonValue::Array(array) => {
+            for (index, element) in array.elems.iter().enumerate() {
+                let matches = match segment {
+                    Segment::Index(i) => *i == index,
+                    Segment::Any => true,
+                    Segment::Key(_) => false,
+                };
+                if !matches {
+                    continue;
+                }
+                if rest.is_empty() {
+                    if let Some(range) = span_range(element.span().indices()) {
+                        ranges.push(with_quotes(range, src));
+                        found = true;
+                    }
+                } else {
+                    found |= resolve(element, rest, src, ranges);
+                }
+            }
+        }
+        _ => {}
+    }
+    found
+}
+
+fn key_name<'a>(member: &KeyValue, src: &'a [u8]) -> &'a str {
+    span_range(member.key.span().indices())
+        .and_then(|range| std::str::from_utf8(&src[range]).ok())
+        .unwrap_or_default()
+        .trim_matches('"')
+}
+
+/// `"key": value`, with the quotes around the key and string values.
+fn member_range(member: &KeyValue, src: &[u8]) -> Option<Range<usize>> {
+    let spans = [
+        span_range(member.span().indices()),
+        span_range(member.key.span().indices()),
+        span_range(member.value.span().indices()),
+    ];
+    let start = spans.iter().flatten().map(|range| range.start).min()?;
+    let end = spans.iter().flatten().map(|range| range.end).max()?;
+    Some(with_quotes(start..end, src))
+}
+
+/// Extends a range over the quotes of a string, which spansy leaves out of string spans.
+fn with_quotes(mut range: Range<usize>, src: &[u8]) -> Range<usize> {
+    if range.start > 0 && src[range.start - 1] == b'"' {
+        range.start -= 1;
+    }
+    if src.get(range.end) == Some(&b'"') {
+        range.end += 1;
+    }
+    range
+}
+
+fn span_range(indices: RangeSet<usize>) -> Option<Range<usize>> {
+    Some(indices.min()?..indices.max()? + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET https://localhost/api/account HTTP/1.1\r\nhost: localhost\r\n\
+        authorization: Bearer secret_token\r\nconnection: close\r\n\r\n";
+
+    fn response(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes()
+    }
+
+    fn policy(reveal_json: &[&str]) -> RedactionPolicy {
+        RedactionPolicy {
+            reveal_json: reveal_json.iter().map(|path| path.to_string()).collect(),
+            ..RedactionPolicy::default()
+        }
+    }
+
+    /// Revealed bytes, with hidden ones shown as `_`.
+    fn render(src: &[u8], ranges: &RangeSet<usize>) -> String {
+        src.iter()
+            .enumerate()
+            .map(|(index, byte)| match ranges.contains(&index) {
+                true => *byte as char,
+                false => '_',
+            })
+            .collect()
+    }
+
+    fn revealed_body(body: &str, paths: &[&str]) -> (String, Vec<String>) {
+        let received = response(body);
+        let reveal = reveal(&policy(paths), REQUEST, &received).unwrap();
+        let rendered = render(&received, &reveal.recv);
+        (
+            rendered[received.len() - body.len()..].to_string(),
+            reveal.unresolved,
+        )
+    }
+
+    #[test]
+    fn hides_redacted_request_headers() {
+        let reveal = reveal(&policy(&[]), REQUEST, &response("{}")).unwrap();
+        let rendered = render(REQUEST, &reveal.sent);
+        assert!(rendered.contains("authorization: ___________________\r\n"));
+        assert!(rendered.starts_with("GET https://localhost/api/account HTTP/1.1\r\n"));
+        assert!(rendered.ends_with("connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn reveals_any_value_kind() {
+        let body =
+            r#"{"name":"Alice","eligible": true,"score" :  42.5,"note":null,"tags":["a","b"]}"#;
+        let (rendered, unresolved) =
+            revealed_body(body, &["name", "eligible", "score", "note", "tags"]);
+        assert_eq!(
+            rendered,
+            r#"_"name":"Alice"_"eligible": true_"score" :  42.5_"note":null_"tags":["a","b"]_"#
+        );
+        assert!(unresolved.is_empty());
+    }
+
+    #[test]
+    fn reveals_nested_and_indexed_fields() {
+        let body = r#"{"accounts": [{"id": "a1", "balance": 100}, {"id": "a2", "balance": 250}]}"#;
+
+        let (rendered, _) = revealed_body(body, &["accounts[1].balance"]);
+        assert_eq!(rendered.replace('_', ""), r#""balance": 250"#);
+
+        let (rendered, _) = revealed_body(body, &["accounts[*].id"]);
+        assert_eq!(rendered.replace('_', ""), r#""id": "a1""id": "a2""#);
+
+        let (rendered, _) = revealed_body(body, &["accounts[0]"]);
+        assert_eq!(rendered.replace('_', ""), r#"{"id": "a1", "balance": 100}"#);
+    }
+
+    #[test]
+    fn reveals_wildcard_members() {
+        let body = r#"{"accounts": {"USD": "1000", "EUR": 500}, "bank": "Mock Bank"}"#;
+        let (rendered, _) = revealed_body(body, &["accounts.*"]);
+        assert_eq!(rendered.replace('_', ""), r#""USD": "1000""EUR": 500"#);
+    }
+
+    #[test]
+    fn reports_unresolved_fields() {
+        let body = r#"{"accounts": [{"id": "a1"}]}"#;
+        let (_, unresolved) = revealed_body(body, &["accounts[0].id", "accounts[3]", "missing"]);
+        assert_eq!(unresolved, vec!["accounts[3]", "missing"]);
+
+        let received = b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello".to_vec();
+        let reveal = reveal(&policy(&["name"]), REQUEST, &received).unwrap();
+        assert_eq!(reveal.unresolved, vec!["name"]);
+    }
+
+    fn regex_policy(rules: &[(&str, RegexScope)]) -> RedactionPolicy {
+        RedactionPolicy {
+            reveal_json: Vec::new(),
+            reveal_regex: rules
+                .iter()
+                .map(|(pattern, scope)| RegexRule {
+                    pattern: pattern.to_string(),
+                    scope: *scope,
+                })
+                .collect(),
+            ..RedactionPolicy::default()
+        }
+    }
+
+    #[test]
+    fn reveals_regex_matches_as_claims() {
+        let body = "<html><p>Holder: Alice</p><p>Status: <b>eligible</b></p></html>";
+        let received =
+            format!("HTTP/1.1 200 OK\r\ncontent-type: text/html\r\nx-tier: gold\r\n\r\n{body}")
+                .into_bytes();
+        let policy = regex_policy(&[
+            (r"Status: <b>(?<status>\w+)</b>", RegexScope::Body),
+            (r"(?i)^x-tier: (?<tier>\w+)$", RegexScope::Headers),
+        ]);
+
+        let reveal = reveal(&policy, REQUEST, &received).unwrap();
+        assert!(reveal.unresolved.is_empty());
+        let rendered = render(&received, &reveal.recv);
+        assert!(rendered.contains("x-tier: gold"));
+        assert!(rendered.contains("Status: <b>eligible</b>"));
+        assert!(!rendered.contains("Alice"));
+
+        let claims =
+            extract_claims(&policy, &redact(&received, &reveal.recv), &reveal.recv).unwrap();
+        assert_eq!(
+            claims,
+            BTreeMap::from([
+                ("status".to_string(), "eligible".to_string()),
+                ("tier".to_string(), "gold".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unrevealed_claims() {
+        let received = b"HTTP/1.1 200 OK\r\ncontent-length: 17\r\n\r\nbalance: 1000 CHF".to_vec();
+        let policy = regex_policy(&[(r"balance: (?<balance>\d+)", RegexScope::Body)]);
+
+        let recv = reveal(&policy, REQUEST, &received).unwrap().recv;
+        let claims = extract_claims(&policy, &redact(&received, &recv), &recv).unwrap();
+        assert_eq!(claims["balance"], "1000");
+
+        // A greedy pattern can't pick up hidden bytes as claim values
+        let greedy = regex_policy(&[(r"balance: (?<balance>.+)", RegexScope::Body)]);
+        let partially_hidden = RangeSet::from(0..received.len() - 3);
+        let view = redact(&received, &partially_hidden);
+        assert!(extract_claims(&greedy, &view, &partially_hidden).is_err());
+
+        // Nor can hiding the end of a number shorten a non-greedy match to `100`
+        let zero = received.len() - 5;
+        assert_eq!(received[zero], b'0');
+        let truncated = RangeSet::from(
+            recv.iter_ranges()
+                .flat_map(|range| {
+                    [
+                        range.start..range.end.min(zero),
+                        range.start.max(zero + 1)..range.end,
+                    ]
+                })
+                .filter(|range| range.start < range.end)
+                .collect::<Vec<_>>(),
+        );
+        let view = redact(&received, &truncated);
+        assert!(extract_claims(&policy, &view, &truncated).is_err());
+
+        // Only the body contains a balance
+        let headers = regex_policy(&[("balance", RegexScope::Headers)]);
+        let unresolved = reveal(&headers, REQUEST, &received).unwrap().unresolved;
+        assert_eq!(unresolved, vec!["balance"]);
+        let authed = RangeSet::from(0..received.len());
+        assert!(extract_claims(&headers, &received, &authed).is_err());
+    }
+
+    #[test]
+    fn reads_claims_next_to_authenticated_nul_bytes() {
+        let received = b"HTTP/1.1 200 OK\r\ncontent-length: 17\r\n\r\nbalance: 1000\0CHF".to_vec();
+        let policy = regex_policy(&[(r"balance: (?<balance>\d+)", RegexScope::Body)]);
+
+        let recv = reveal(&policy, REQUEST, &received).unwrap().recv;
+        let claims = extract_claims(&policy, &redact(&received, &recv), &recv).unwrap();
+        assert_eq!(claims["balance"], "1000");
+    }
+
+    fn head_policy(expected_content_type: Option<&str>) -> RedactionPolicy {
+        RedactionPolicy {
+            reveal_json: Vec::new(),
+            reveal_status_line: true,
+            reveal_response_headers: vec!["x-request-id".to_string()],
+            expected_content_type: expected_content_type.map(str::to_string),
+            ..RedactionPolicy::default()
+        }
+    }
+
+    #[test]
+    fn reveals_status_line_and_headers() {
+        let received = b"HTTP/1.1 200 OK\r\ncontent-type: application/json; charset=utf-8\r\n\
+            set-cookie: session=secret\r\nx-request-id: 42\r\ncontent-length: 2\r\n\r\n{}";
+        let policy = head_policy(Some("application/json"));
+
+        let revealed = reveal(&policy, REQUEST, received).unwrap();
+        assert!(revealed.unresolved.is_empty());
+        let rendered = render(received, &revealed.recv);
+        assert!(rendered.starts_with("HTTP/1.1 200 OK\r\ncontent-type: application/json"));
+        assert!(rendered.contains("\r\nx-request-id: 42\r\n"));
+        assert!(!rendered.contains("secret"));
+        assert!(!rendered.contains("content-length: 2"));
+
+        let verifier_view = redact(received, &revealed.recv);
+        check_response_head(&policy, &verifier_view, &revealed.recv).unwrap();
+        assert!(check_response_head(
+            &head_policy(Some("text/html")),
+            &verifier_view,
+            &revealed.recv
+        )
+        .is_err());
+
+        // The prover didn't reveal the content type
+        let recv = reveal(&head_policy(None), REQUEST, received).unwrap().recv;
+        let verifier_view = redact(received, &recv);
+        check_response_head(&head_policy(None), &verifier_view, &recv).unwrap();
+        assert!(check_response_head(&policy, &verifier_view, &recv).is_err());
+    }
+
+    #[test]
+    fn rejects_hidden_end_of_headers() {
+        let received = b"HTTP/1.1 200 OK\r\nx-request-id: 42\r\n\r\n\
+            note\r\ncontent-type: text/html\r\n\r\n";
+        let policy = head_policy(Some("text/html"));
+        let headers_end = 33;
+        assert_eq!(&received[headers_end..headers_end + 4], b"\r\n\r\n");
+
+        // Honestly revealed, the content type is part of the body
+        let recv = reveal(&policy, REQUEST, received).unwrap().recv;
+        assert!(check_response_head(&policy, &redact(received, &recv), &recv).is_err());
+
+        // Hiding the real end of headers, alone or with the body up to a line break
+        for hidden_end in [headers_end + 4, headers_end + 8] {
+            let recv = RangeSet::from([0..headers_end, hidden_end..received.len()]);
+            let err = check_response_head(&policy, &redact(received, &recv), &recv).unwrap_err();
+            assert!(err.to_string().contains("partially revealed"), "{err}");
+        }
+    }
+
+    #[test]
+    fn rejects_error_status() {
+        let received = b"HTTP/1.1 404 Not Found\r\ncontent-type: application/json\r\n\r\n";
+        let policy = head_policy(Some("application/json"));
+
+        let recv = reveal(&policy, REQUEST, received).unwrap().recv;
+        let err = check_response_head(&policy, &redact(received, &recv), &recv).unwrap_err();
+        assert!(err.to_string().contains("404"), "{err}");
+
+        // Without a revealed status line there is nothing to check against
+        let hidden = RangeSet::from(0..0);
+        assert!(check_response_head(&policy, &redact(received, &hidden), &hidden).is_err());
+    }
+
+    #[test]
+    fn requires_authenticated_content() {
+        let received = response(r#"{"organization": "Ethereum Foundation", "balance": 100}"#);
+        let required = RedactionPolicy {
+            required_content: vec!["Ethereum Foundation".to_string()],
+            ..policy(&["organization"])
+        };
+
+        let recv = reveal(&required, REQUEST, &received).unwrap().recv;
+        check_required_content(&required, &redact(&received, &recv), &recv).unwrap();
+
+        let recv = reveal(&policy(&["balance"]), REQUEST, &received)
+            .unwrap()
+            .recv;
+        assert!(check_required_content(&required, &received, &recv).is_err());
+        let optional = RedactionPolicy {
+            required_content: Vec::new(),
+            ..required
+        };
+        check_required_content(&optional, &received, &recv).unwrap();
+    }
+
+    #[test]
+    fn previews_policy() {
+        let received = response(r#"{"name": "Alice", "balance": 100}"#);
+        let preview = preview(&policy(&["balance", "missing"]), REQUEST, &received).unwrap();
+
+        assert_eq!(preview.unresolved, vec!["missing"]);
+        assert_eq!(preview.received.len(), 1);
+        assert!(preview.received_redacted.ends_with(r#""balance": 100🙈"#));
+        assert!(!preview.received_redacted.contains("Alice"));
+        assert!(preview.sent_redacted.contains("authorization: 🙈🙈🙈"));
+        assert_eq!(preview.sent.first().map(|range| range.start), Some(0));
+
+        // Revealed NUL bytes aren't shown as hidden
+        let received = b"HTTP/1.1 200 OK\r\ncontent-length: 17\r\n\r\nbalance: 1000\0CHF";
+        let policy = regex_policy(&[(r"balance: \d+", RegexScope::Body)]);
+        let preview = super::preview(&policy, REQUEST, received).unwrap();
+        assert!(preview.received_redacted.ends_with("balance: 1000\0🙈🙈🙈"));
+    }
+
+    #[test]
+    fn parses_paths() {
+        use Segment::*;
+        assert_eq!(
+            parse_path("accounts[0].balance").unwrap(),
+            vec![Key("accounts".into()), Index(0), Key("balance".into())]
+        );
+        assert_eq!(
+            parse_path("items[*][2].*").unwrap(),
+            vec![Key("items".into()), Any, Index(2), Any]
+        );
+        for invalid in ["", "a..b", "a[", "a[x]", "a[0]b", "[0]"] {
+            assert!(parse_path(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! Hot reload of the configuration file.
+//!
+//! The file is checked for changes periodically and reloaded unconditionally on SIGHUP. A
+//! reloaded configuration must load completely before it replaces the current one, so a
+//! broken file leaves the server as it was. Running sessions keep the configuration they
+//! started with.
+//!
+//! Reloading applies the target (`server_uri`, `target_tls`, `protocol` and
+//! `protocol_targets`), the verification rules (`redaction`, `policy_version` and
+//! `verifier_limits`), `timeouts`, `trusted_proxies`, `rate_limit`, the notary key and
+//! `on_chain`. Listeners, the audit log, webhooks, the admin API and telemetry need a restart;
+//! changes to them are logged and reported by the admin API until then.
+
+use crate::{
+    config::Config, load_claim_submitter, load_notary, load_target_roots, rate_limit::RateLimiter,
+    ServerGlobals, SharedGlobals,
+};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+impl ServerGlobals {
+    /// Globals for `config`, replacing `previous`. Services whose configuration didn't change
+    /// are kept, along with their state.
+    pub(crate) fn reloaded(
+        &self,
+        previous: &Config,
+        config: &Config,
+    ) -> Result<Self, eyre::ErrReport> {
+        let target_roots = load_target_roots(config)?;
+        let protocol = config.protocol_for(&config.server_domain())?;
+
+        // Running sessions keep submitting with the current submitter
+        let claim_submitter = if config.on_chain == previous.on_chain {
+            self.claim_submitter.clone()
+        } else {
+            load_claim_submitter(config, self.claim_submitter.as_deref())?
+        };
+
+        // Sessions awaiting their attestation request stay with the notary
+        let (notary, notary_update) = if config.notary == previous.notary {
+            (self.notary.clone(), None)
+        } else {
+            match (&self.notary, load_notary(config)?) {
+                (Some(notary), Some(reloaded)) => (Some(notary.clone()), Some(reloaded)),
+                (_, reloaded) => (reloaded.map(Arc::new), None),
+            }
+        };
+
+        let rate_limiter = if config.rate_limit == previous.rate_limit {
+            self.rate_limiter.clone()
+        } else {
+            config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)))
+        };
+
+        if let (Some(notary), Some(reloaded)) = (&notary, notary_update) {
+            notary.update(reloaded);
+        }
+
+        Ok(Self {
+            server_uri: config.server_uri.clone(),
+            timeouts: config.timeouts,
+            policy_version: config.policy_version.clone(),
+            audit_log: self.audit_log.clone(),
+            claim_submitter,
+            webhooks: self.webhooks.clone(),
+            notary,
+            target_roots,
+            redaction: config.redaction.clone(),
+            protocol,
+            verifier_limits: config.verifier_limits.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            rate_limiter,
+            sessions: self.sessions.clone(),
+            observers: self.observers.clone(),
+        })
+    }
+}
+
+/// Outcome of the reloads so far, as reported by the admin API
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct ReloadStatus {
+    pub reloads: u64,
+    pub failures: u64,
+    /// Unix time in seconds of the last successful reload
+    pub last_reload_at: Option<u64>,
+    /// Error of the last reload, cleared by a successful one
+    pub last_error: Option<String>,
+    /// Changed fields that only apply after a restart
+    pub restart_required: Vec<&'static str>,
+}
+
+/// Reloads the configuration file into the server globals.
+#[derive(Debug)]
+pub(crate) struct ConfigReloader {
+    path: PathBuf,
+    globals: SharedGlobals,
+    /// Configuration the current globals were loaded from
+    config: Mutex<Config>,
+    /// Configuration the server started with
+    started: Config,
+    modified: Mutex<Option<SystemTime>>,
+    status: Mutex<ReloadStatus>,
+}
+
+impl ConfigReloader {
+    /// Reloads `path`, from which `config` was loaded into `globals`.
+    pub fn new(path: PathBuf, config: Config, globals: SharedGlobals) -> Self {
+        let modified = modification_time(&path);
+        Self {
+            path,
+            globals,
+            started: config.clone(),
+            config: Mutex::new(config),
+            modified: Mutex::new(modified),
+            status: Mutex::new(ReloadStatus::default()),
+        }
+    }
+
+    /// Reloads the configuration if the file changed, or regardless with `force`. Returns
+    /// whether it was reloaded.
+    pub fn reload(&self, force: bool) -> Result<bool, eyre::ErrReport> {
+        let modified = modification_time(&self.path);
+        if !force && modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        // A broken file is reported once, not on every check
+        *self.modified.lock().unwrap() = modified;
+
+        let mut current = self.config.lock().unwrap();
+        let result = Config::load(&self.path).and_then(|config| {
+            let globals = self.globals.current().reloaded(&current, &config)?;
+            Ok((config, globals))
+        });
+
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok((config, globals)) => {
+                let restart_required = restart_required(&self.started, &config);
+                if !restart_required.is_empty() {
+                    warn!(
+                        "Changes to {} take effect after a restart",
+                        restart_required.join(", ")
+                    );
+                }
+                self.globals.replace(globals);
+                *current = config;
+                status.reloads += 1;
+                status.last_reload_at = Some(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                );
+                status.last_error = None;
+                status.restart_required = restart_required;
+                Ok(true)
+            }
+            Err(err) => {
+                status.failures += 1;
+                status.last_error = Some(err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    pub fn status(&self) -> ReloadStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Checks the file for changes every `config_reload_interval_secs`, and reloads it on
+    /// SIGHUP.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let reloader = self.clone();
+        let interval_secs = self.config.lock().unwrap().config_reload_interval_secs;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+            interval.tick().await;
+            let mut signal = listen_hangup();
+            loop {
+                let force = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = hangup(&mut signal) => true,
+                };
+                match reloader.reload(force) {
+                    Ok(true) => info!("Reloaded configuration from {}", reloader.path.display()),
+                    Ok(false) => {}
+                    Err(err) => error!(
+                        "Failed to reload configuration from {}, keeping the current one: {err}",
+                        reloader.path.display()
+                    ),
+                }
+            }
+        })
+    }
+}
+
+/// Fields of `config` that differ from `started` but can't be reloaded.
+fn restart_required(started: &Config, config: &Config) -> Vec<&'static str> {
+    [
+        ("ws_host", started.ws_host != config.ws_host),
+        ("ws_port", started.ws_port != config.ws_port),
+        ("listen", started.listen != config.listen),
+        (
+            "proxy_protocol",
+            started.proxy_protocol != config.proxy_protocol,
+        ),
+        (
+            "wstcp_proxy_host",
+            started.wstcp_proxy_host != config.wstcp_proxy_host,
+        ),
+        (
+            "wstcp_proxy_port",
+            started.wstcp_proxy_port != config.wstcp_proxy_port,
+        ),
+        (
+            "audit_log_path",
+            started.audit_log_path != config.audit_log_path,
+        ),
+        ("webhooks", started.webhooks != config.webhooks),
+        ("admin_token", started.admin_token != config.admin_token),
+        ("admin_listen", started.admin_listen != config.admin_listen),
+        ("listener_tls", started.listener_tls != config.listener_tls),
+        ("telemetry", started.telemetry != config.telemetry),
+        (
+            "config_reload_interval_secs",
+            started.config_reload_interval_secs != config.config_reload_interval_secs,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(field, changed)| changed.then_some(field))
+    .collect()
+}
+
+fn modification_time(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+type HangupSignal = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type HangupSignal = ();
+
+#[cfg(unix)]
+fn listen_hangup() -> HangupSignal {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup())
+        .map_err(|err| error!("Failed to listen for SIGHUP: {err}"))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn listen_hangup() -> HangupSignal {}
+
+/// Resolves on the next SIGHUP, never when it isn't available.
+async fn hangup(signal: &mut HangupSignal) {
+    #[cfg(unix)]
+    if let Some(signal) = signal {
+        signal.recv().await;
+        return;
+    }
+    let _ = signal;
+    std::future::pending::<()>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{OnChainConfig, RateLimitConfig},
+        sessions::Sessions,
+    };
+
+    #[test]
+    fn swaps_valid_configs_and_keeps_the_current_one_otherwise() {
+        let path = std::env::temp_dir().join(format!("prover-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "policy_version = \"1\"\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        let globals =
+            SharedGlobals::new(ServerGlobals::load(&config, Arc::new(Sessions::new())).unwrap());
+        let reloader = ConfigReloader::new(path.clone(), config, globals.clone());
+        let snapshot = globals.current();
+
+        assert!(!reloader.reload(false).unwrap());
+        std::fs::write(&path, "policy_version = \"2\"\n").unwrap();
+        assert!(reloader.reload(true).unwrap());
+        assert_eq!(globals.current().policy_version, "2");
+        assert_eq!(snapshot.policy_version, "1");
+
+        std::fs::write(&path, "policy_version = [\n").unwrap();
+        assert!(reloader.reload(true).is_err());
+        assert_eq!(globals.current().policy_version, "2");
+        let status = reloader.status();
+        assert_eq!((status.reloads, status.failures), (1, 1));
+        assert!(status.last_error.is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reports_changes_that_need_a_restart() {
+        let path = std::env::temp_dir().join(format!("prover-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "policy_version = \"1\"\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        let globals =
+            SharedGlobals::new(ServerGlobals::load(&config, Arc::new(Sessions::new())).unwrap());
+        let reloader = ConfigReloader::new(path.clone(), config, globals);
+
+        std::fs::write(
+            &path,
+            "policy_version = \"2\"\nadmin_token = \"secret\"\nws_port = 1234\n",
+        )
+        .unwrap();
+        assert!(reloader.reload(true).unwrap());
+        assert_eq!(
+            reloader.status().restart_required,
+            ["ws_port", "admin_token"]
+        );
+
+        // Reverting the change clears it
+        std::fs::write(&path, "policy_version = \"3\"\n").unwrap();
+        assert!(reloader.reload(true).unwrap());
+        assert!(reloader.status().restart_required.is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_only_the_changed_services() {
+        let on_chain = OnChainConfig {
+            oracle_address: format!("0x{}", "11".repeat(20)),
+            private_key: "46".repeat(32),
+            ..Default::default()
+        };
+        let previous = Config {
+            rate_limit: Some(RateLimitConfig::default()),
+            on_chain: Some(on_chain.clone()),
+            ..Default::default()
+        };
+        let globals = ServerGlobals::load(&previous, Arc::new(Sessions::new())).unwrap();
+        let rate_limiter = globals.rate_limiter.clone().unwrap();
+        let claim_submitter = globals.claim_submitter.clone().unwrap();
+
+        let unchanged = globals.reloaded(&previous, &previous.clone()).unwrap();
+        assert!(Arc::ptr_eq(
+            unchanged.rate_limiter.as_ref().unwrap(),
+            &rate_limiter
+        ));
+        assert!(Arc::ptr_eq(
+            unchanged.claim_submitter.as_ref().unwrap(),
+            &claim_submitter
+        ));
+
+        let config = Config {
+            rate_limit: Some(RateLimitConfig {
+                max_sessions: 1,
+                ..Default::default()
+            }),
+            on_chain: Some(OnChainConfig {
+                claim_ttl_secs: on_chain.claim_ttl_secs + 1,
+                ..on_chain
+            }),
+            ..previous.clone()
+        };
+        let changed = globals.reloaded(&previous, &config).unwrap();
+        assert!(!Arc::ptr_eq(
+            changed.rate_limiter.as_ref().unwrap(),
+            &rate_limiter
+        ));
+        assert!(!Arc::ptr_eq(
+            changed.claim_submitter.as_ref().unwrap(),
+            &claim_submitter
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+//! Ownership of the tasks of running sessions.
+//!
+//! Every WebSocket session runs under [`Sessions`], which lists it for the admin API until it
+//! ends. A shutdown, or an administrator cancelling the session, cancels its operation and
+//! waits until its result is reported. Work a session spawns onto other threads runs in
+//! [`OwnedTask`]s, aborted as soon as the session drops them, whether it finished, timed out
+//! or was cancelled. An aborted task drops what it owns, closing the upstream connection.
+//! Work that outlives its session, like submitting its claim, runs with
+//! [`Sessions::follow_up`], so a shutdown still waits for it.
+
+use crate::{telemetry::Phase, webhook::SessionKind};
+use eyre::eyre;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use uuid::Uuid;
+
+tokio::task_local! {
+    /// Session of the future being polled
+    static CURRENT: Arc<ActiveSession>;
+}
+
+/// Session about to start
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub kind: SessionKind,
+    pub target: String,
+    pub client_ip: Option<IpAddr>,
+    /// Wallet the session is proving eligibility for
+    pub wallet: Option<String>,
+}
+
+/// Running session, as listed by the admin API
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub kind: SessionKind,
+    pub target: String,
+    pub client_ip: Option<IpAddr>,
+    /// `None` until the first phase starts
+    pub phase: Option<Phase>,
+    /// Unix time in seconds
+    pub started_at: u64,
+    pub age_secs: u64,
+}
+
+#[derive(Debug)]
+struct ActiveSession {
+    session: Session,
+    started: Instant,
+    started_at: u64,
+    phase: Mutex<Option<Phase>>,
+    cancel: CancellationToken,
+}
+
+type ActiveSessions = Arc<Mutex<HashMap<Uuid, Arc<ActiveSession>>>>;
+
+/// Sessions running on the server
+#[derive(Debug, Default)]
+pub struct Sessions {
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+    active: ActiveSessions,
+    /// Targets that don't accept new sessions
+    maintenance: Mutex<BTreeSet<String>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs a session, listed and counted as running until it has reported its result.
+    pub fn track<F: Future>(&self, session: Session, future: F) -> impl Future<Output = F::Output> {
+        let id = session.id;
+        let active = Arc::new(ActiveSession {
+            session,
+            started: Instant::now(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            phase: Mutex::new(None),
+            cancel: self.shutdown.child_token(),
+        });
+        self.active.lock().unwrap().insert(id, active.clone());
+        let deregister = Deregister {
+            active: self.active.clone(),
+            id,
+        };
+
+        self.tracker.track_future(CURRENT.scope(active, async move {
+            let _deregister = deregister;
+            future.await
+        }))
+    }
+
+    /// Runs `operation` of a session until it completes, or the session is cancelled by an
+    /// administrator or the server shutting down.
+    pub async fn cancellable<T>(
+        &self,
+        operation: impl Future<Output = Result<T, eyre::ErrReport>>,
+    ) -> Result<T, eyre::ErrReport> {
+        let cancel = CURRENT
+            .try_with(|session| session.cancel.clone())
+            .unwrap_or_else(|_| self.shutdown.clone());
+        tokio::select! {
+            result = operation => result,
+            _ = cancel.cancelled() => Err(if self.shutdown.is_cancelled() {
+                eyre!("Session cancelled by server shutdown")
+            } else {
+                eyre!("Session cancelled by an administrator")
+            }),
+        }
+    }
+
+    /// Runs `future`, started by a session, past the end of the session. It isn't cancelled,
+    /// but [`Sessions::shutdown`] waits for it.
+    pub fn follow_up<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(future);
+    }
+
+    /// Running sessions, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|active| {
+                (
+                    active.started,
+                    SessionInfo {
+                        id: active.session.id,
+                        kind: active.session.kind,
+                        target: active.session.target.clone(),
+                        client_ip: active.session.client_ip,
+                        phase: *active.phase.lock().unwrap(),
+                        started_at: active.started_at,
+                        age_secs: active.started.elapsed().as_secs(),
+                    },
+                )
+            })
+            .collect();
+        sessions.sort_by_key(|(started, _)| *started);
+        sessions.into_iter().map(|(_, session)| session).collect()
+    }
+
+    /// Cancels the session `id`, returning whether it is running.
+    pub fn cancel(&self, id: Uuid) -> bool {
+        match self.active.lock().unwrap().get(&id) {
+            Some(active) => {
+                active.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops or resumes accepting new sessions for `target`.
+    pub fn set_maintenance(&self, target: &str, enabled: bool) {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        if enabled {
+            maintenance.insert(target.to_string());
+        } else {
+            maintenance.remove(target);
+        }
+    }
+
+    /// Targets in maintenance mode.
+    pub fn maintenance(&self) -> Vec<String> {
+        self.maintenance.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn in_maintenance(&self, target: &str) -> bool {
+        self.maintenance.lock().unwrap().contains(target)
+    }
+
+    /// Cancels every session and waits until all of them reported their result, and their
+    /// follow-ups finished.
+    pub async fn shutdown(&self) {
+        self.tracker.close();
+        self.shutdown.cancel();
+        self.tracker.wait().await;
+    }
+}
+
+/// Records `phase` for the admin API, when running under [`Sessions::track`].
+pub(crate) fn record_phase(phase: Phase) {
+    let _ = CURRENT.try_with(|session| *session.phase.lock().unwrap() = Some(phase));
+}
+
+/// Removes a session from the list when it ends, even by panicking
+struct Deregister {
+    active: ActiveSessions,
+    id: Uuid,
+}
+
+impl Drop for Deregister {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+/// Spawned task that is aborted when dropped
+#[derive(Debug)]
+pub struct OwnedTask<T>(JoinHandle<T>);
+
+impl<T: Send + 'static> OwnedTask<T> {
+    pub fn spawn<F>(future: F) -> Self
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        Self(tokio::spawn(future))
+    }
+}
+
+impl<T> Future for OwnedTask<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for OwnedTask<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        future::pending,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        runtime::Handle,
+        time::timeout,
+    };
+
+    fn session() -> Session {
+        Session {
+            id: Uuid::new_v4(),
+            kind: SessionKind::Verify,
+            target: "swissbank.tlsnotary.org".into(),
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            wallet: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn aborts_owned_tasks_with_their_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Handle::current().metrics();
+        let baseline = metrics.num_alive_tasks();
+
+        // Like the prover, keeps the upstream connection in one task and works in another
+        let session = async {
+            let upstream = TcpStream::connect(address).await.unwrap();
+            let connection = OwnedTask::spawn(async move {
+                let _upstream = upstream;
+                pending::<()>().await
+            });
+            let worker = OwnedTask::spawn(pending::<()>());
+            let _ = tokio::join!(connection, worker);
+        };
+        let (session, accepted) = tokio::join!(
+            timeout(Duration::from_millis(50), session),
+            listener.accept()
+        );
+        assert!(session.is_err());
+
+        let (mut upstream, _) = accepted.unwrap();
+        let read = timeout(Duration::from_secs(5), upstream.read(&mut [0; 1])).await;
+        assert_eq!(read.unwrap().unwrap(), 0, "upstream connection left open");
+
+        for _ in 0..100 {
+            if metrics.num_alive_tasks() == baseline {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("{} tasks left", metrics.num_alive_tasks() - baseline);
+    }
+
+    #[tokio::test]
+    async fn shutdown_cancels_sessions_and_waits_for_them() {
+        let sessions = Arc::new(Sessions::new());
+        let reported = Arc::new(AtomicBool::new(false));
+
+        let future = {
+            let (sessions, reported) = (sessions.clone(), reported.clone());
+            async move {
+                let result = sessions
+                    .cancellable(pending::<Result<(), eyre::ErrReport>>())
+                    .await;
+                assert!(result.is_err());
+                tokio::task::yield_now().await;
+                reported.store(true, Ordering::SeqCst);
+            }
+        };
+        tokio::spawn(sessions.track(session(), future));
+        tokio::task::yield_now().await;
+
+        timeout(Duration::from_secs(5), sessions.shutdown())
+            .await
+            .unwrap();
+        assert!(reported.load(Ordering::SeqCst));
+        assert!(sessions.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_follow_ups() {
+        let sessions = Sessions::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        sessions.follow_up({
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                finished.store(true, Ordering::SeqCst);
+            }
+        });
+
+        timeout(Duration::from_secs(5), sessions.shutdown())
+            .await
+            .unwrap();
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn lists_and_cancels_sessions() {
+        let sessions = Arc::new(Sessions::new());
+        let session = session();
+        let future = {
+            let sessions = sessions.clone();
+            async move {
+                Phase::Setup.enter();
+                sessions
+                    .cancellable(pending::<Result<(), eyre::ErrReport>>())
+                    .await
+            }
+        };
+        let running = tokio::spawn(sessions.track(session.clone(), future));
+        tokio::task::yield_now().await;
+
+        let listed = sessions.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, session.id);
+        assert_eq!(listed[0].kind, SessionKind::Verify);
+        assert_eq!(listed[0].phase, Some(Phase::Setup));
+
+        assert!(!sessions.cancel(Uuid::new_v4()));
+        assert!(sessions.cancel(session.id));
+        let err = running.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Session cancelled by an administrator");
+        assert!(sessions.list().is_empty());
+    }
+
+    #[test]
+    fn toggles_maintenance_per_target() {
+        let sessions = Sessions::new();
+        sessions.set_maintenance("swissbank.tlsnotary.org", true);
+        assert!(sessions.in_maintenance("swissbank.tlsnotary.org"));
+        assert!(!sessions.in_maintenance("example.com"));
+        assert_eq!(sessions.maintenance(), ["swissbank.tlsnotary.org"]);
+
+        sessions.set_maintenance("swissbank.tlsnotary.org", false);
+        assert!(sessions.maintenance().is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+//! Correlation of the logs of concurrent sessions.
+//!
+//! Every connection runs in a `connection` span with the peer address, and every WebSocket
+//! session in a `session` span with its ID, socket type, target, client IP and current
+//! [`Phase`]. With `--log-format json` each log line carries these fields, so one session
+//! can be filtered out of a busy server.
+//!
+//! Each phase also gets a child span of the session, which [`otlp_layer`] exports to an
+//! OpenTelemetry collector for timing slow proofs. The exported session span carries the
+//! `error_code` attribute of an expired deadline, like `response_timeout`, and
+//! [`otlp_meter_provider`] exports the `session.timeouts` counter labelled with it.
+
+use crate::{
+    config::TelemetryConfig,
+    observer::{self, Event},
+    sessions,
+};
+use eyre::eyre;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    runtime,
+    trace::TracerProvider,
+    Resource,
+};
+use serde::Serialize;
+use tracing::{debug, info_span, Span, Subscriber};
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// Step of a session, recorded in the `phase` field of its span
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// MPC setup between prover and verifier
+    Setup,
+    /// TCP connection to the target server
+    Connect,
+    /// MPC-TLS handshake with the target server
+    Handshake,
+    /// HTTP request and response over MPC-TLS
+    Request,
+    /// Proving the revealed transcript
+    Prove,
+    /// Checking the revealed transcript against the policy
+    Verify,
+    /// Closing the session and reporting its result
+    Close,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Setup => "setup",
+            Self::Connect => "connect",
+            Self::Handshake => "handshake",
+            Self::Request => "request",
+            Self::Prove => "prove",
+            Self::Verify => "verify",
+            Self::Close => "close",
+        }
+    }
+
+    /// Records the phase on the current session span and for the admin API, and reports it
+    /// to the session observers.
+    pub fn enter(self) {
+        Span::current().record("phase", self.as_str());
+        sessions::record_phase(self);
+        observer::notify(Event::Phase(self));
+        debug!("Entering {} phase", self.as_str());
+    }
+
+    /// Enters the phase and returns its span, a child of the current one, to instrument
+    /// the work of the phase with.
+    pub fn span(self) -> Span {
+        self.enter();
+        info_span!("phase", otel.name = self.as_str(), phase = self.as_str())
+    }
+}
+
+/// Layer exporting spans over OTLP/HTTP to `config.otlp_endpoint`.
+///
+/// Must be called within a Tokio runtime. Shut the returned provider down before exiting
+/// to flush the pending spans.
+pub fn otlp_layer<S>(
+    config: &TelemetryConfig,
+) -> Result<(impl Layer<S>, TracerProvider), eyre::ErrReport>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()
+        .map_err(|err| eyre!("Failed to create OTLP exporter: {err}"))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(resource(config))
+        .build();
+    let tracer = provider.tracer("prover-server");
+
+    Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+}
+
+/// Meter provider exporting metrics over OTLP/HTTP to `config.otlp_metrics_endpoint`,
+/// installed as the global one.
+///
+/// Must be called within a Tokio runtime. Shut the returned provider down before exiting
+/// to flush the pending metrics.
+pub fn otlp_meter_provider(config: &TelemetryConfig) -> Result<SdkMeterProvider, eyre::ErrReport> {
+    let exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_metrics_endpoint)
+        .build()
+        .map_err(|err| eyre!("Failed to create OTLP metric exporter: {err}"))?;
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build())
+        .with_resource(resource(config))
+        .build();
+    global::set_meter_provider(provider.clone());
+    Ok(provider)
+}
+
+fn resource(config: &TelemetryConfig) -> Resource {
+    Resource::new([KeyValue::new("service.name", config.service_name.clone())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeouts::{count_expired, Deadline};
+    use axum::{body::Bytes, extract::State, routing::post, Router};
+    use std::sync::{Arc, Mutex};
+    use tracing::{instrument::WithSubscriber, Instrument};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Exports = Arc<Mutex<Vec<Bytes>>>;
+
+    /// Stand-in for an OpenTelemetry collector, keeping the bodies of export requests
+    async fn start_collector() -> (TelemetryConfig, Exports) {
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let export = post(|State(exports): State<Exports>, body: Bytes| async move {
+            exports.lock().unwrap().push(body);
+        });
+        let router = Router::new()
+            .route("/v1/traces", export.clone())
+            .route("/v1/metrics", export)
+            .with_state(exports.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let config = TelemetryConfig {
+            otlp_endpoint: format!("http://{address}/v1/traces"),
+            otlp_metrics_endpoint: format!("http://{address}/v1/metrics"),
+            service_name: "prover-server-test".into(),
+        };
+        (config, exports)
+    }
+
+    fn assert_exported(exports: &Exports, names: &[&str]) {
+        let exports = exports.lock().unwrap();
+        let exported: Vec<u8> = exports.iter().flat_map(|body| body.to_vec()).collect();
+        for name in names {
+            assert!(
+                exported
+                    .windows(name.len())
+                    .any(|window| window == name.as_bytes()),
+                "{name} was not exported"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_phase_spans() {
+        let (config, exports) = start_collector().await;
+        let (layer, provider) = otlp_layer(&config).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        async {
+            let session = info_span!(
+                "session",
+                phase = tracing::field::Empty,
+                error_code = tracing::field::Empty,
+            );
+            async {
+                Phase::Setup.span().in_scope(|| debug!("Setting up"));
+                Span::current().record("error_code", Deadline::Setup.code());
+                async { debug!("Connecting") }
+                    .instrument(Phase::Connect.span())
+                    .await;
+            }
+            .instrument(session)
+            .await;
+        }
+        .with_subscriber(subscriber)
+        .await;
+
+        // Flushing blocks until the batch processor exported everything
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_exported(
+            &exports,
+            &[
+                "session",
+                "setup",
+                "connect",
+                "prover-server-test",
+                "error_code",
+                "setup_timeout",
+            ],
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_timeout_counts() {
+        let (config, exports) = start_collector().await;
+        let provider = otlp_meter_provider(&config).unwrap();
+
+        count_expired(Deadline::Response);
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_exported(
+            &exports,
+            &["session.timeouts", "error_code", "response_timeout"],
+        );
+    }
+}
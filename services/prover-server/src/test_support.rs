@@ -0,0 +1,235 @@
+//! Helpers for end-to-end tests that run without network access.
+//!
+//! [`MockBank`] is a local HTTPS server with a freshly generated self-signed certificate
+//! for `localhost`, serving JSON fixtures. Point the prover at [`MockBank::uri`] and trust
+//! [`MockBank::roots`] on both sides, or [`MockBank::certificate_pem`] in `target_tls`.
+
+use crate::{peer::PeerAddr, serve_connection, tls, tls::TargetRoots};
+use axum::{http::header, routing::get, Router};
+use hyper::server::conn::http1;
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_rustls::{
+    rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    TlsAcceptor,
+};
+use tracing::debug;
+
+/// Balances in the SwissBank format expected by the prover's redaction rules
+pub const BALANCES_FIXTURE: &str = concat!(
+    r#"{"organization": "Ethereum Foundation", "bank": "Mock Bank", "#,
+    r#""accounts": {"USD": "1000", "EUR": "500", "CHF": "250"}}"#
+);
+
+/// Account details
+pub const ACCOUNT_FIXTURE: &str =
+    r#"{"id": "acc_1234", "holder": "Alice", "country": "CH", "verified": true}"#;
+
+/// JSON bodies served by a [`MockBank`], keyed by path. `None` never answers.
+#[derive(Clone, Debug)]
+pub struct Fixtures(BTreeMap<String, Option<String>>);
+
+impl Fixtures {
+    /// No fixtures, every path returns 404.
+    pub fn empty() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Serves `body` as `application/json` on `path`.
+    pub fn with(mut self, path: &str, body: impl Into<String>) -> Self {
+        self.0.insert(path.to_string(), Some(body.into()));
+        self
+    }
+
+    /// Never answers requests to `path`, keeping the connection open until the client
+    /// closes it.
+    pub fn stalled(mut self, path: &str) -> Self {
+        self.0.insert(path.to_string(), None);
+        self
+    }
+
+    fn router(self) -> Router {
+        let mut router = Router::new();
+        for (path, body) in self.0 {
+            router = match body {
+                Some(body) => {
+                    let handler =
+                        move || async move { ([(header::CONTENT_TYPE, "application/json")], body) };
+                    router.route(&path, get(handler))
+                }
+                None => router.route(&path, get(std::future::pending::<()>)),
+            };
+        }
+        router
+    }
+}
+
+impl Default for Fixtures {
+    /// `/api/account` and `/api/balances`
+    fn default() -> Self {
+        Self::empty()
+            .with("/api/account", ACCOUNT_FIXTURE)
+            .with("/api/balances", BALANCES_FIXTURE)
+    }
+}
+
+/// Local HTTPS server standing in for a bank, stopped when dropped
+pub struct MockBank {
+    address: SocketAddr,
+    certificate: Vec<u8>,
+    certificate_pem: String,
+    /// Connections accepted and not closed yet
+    connections: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl MockBank {
+    /// Starts serving `fixtures` on a random loopback port.
+    pub async fn start(fixtures: Fixtures) -> Result<Self, eyre::ErrReport> {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let certificate = certified.cert.der().to_vec();
+        let certificate_pem = certified.cert.pem();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let acceptor = TlsAcceptor::from(Arc::new(tls::server_config_with(
+            vec![certified.cert.der().clone()],
+            PrivateKeyDer::from(key),
+        )?));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let router = fixtures.router();
+        let protocol = Arc::new(http1::Builder::new());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let task = tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let acceptor = acceptor.clone();
+                    let router = router.clone();
+                    let protocol = protocol.clone();
+                    let connections = connections.clone();
+                    let peer = PeerAddr(stream.peer_addr().ok());
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => serve_connection(stream, router, protocol, peer).await,
+                            Err(err) => debug!("Mock bank TLS handshake failed: {err}"),
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }
+        });
+
+        Ok(Self {
+            address,
+            certificate,
+            certificate_pem,
+            connections,
+            task,
+        })
+    }
+
+    /// `https://localhost:<port><path>`
+    pub fn uri(&self, path: &str) -> http::Uri {
+        format!("https://localhost:{}{path}", self.address.port())
+            .parse()
+            .expect("mock bank URI is valid")
+    }
+
+    /// DER-encoded self-signed certificate of the server.
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// PEM-encoded self-signed certificate of the server, for `target_tls.root_certs`.
+    pub fn certificate_pem(&self) -> &str {
+        &self.certificate_pem
+    }
+
+    /// Number of client connections the server hasn't closed yet.
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Trusts only the mock bank certificate.
+    pub fn roots(&self) -> TargetRoots {
+        TargetRoots {
+            certs: vec![self.certificate.clone()],
+            custom_only: true,
+        }
+    }
+}
+
+impl Drop for MockBank {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_fixtures_over_tls() {
+        let bank = MockBank::start(Fixtures::default()).await.unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(bank.certificate()).unwrap())
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(bank.uri("/api/account").to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let account: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(account["holder"], "Alice");
+
+        let response = client
+            .get(bank.uri("/api/missing").to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn counts_connections_to_stalled_paths() {
+        let bank = MockBank::start(Fixtures::empty().stalled("/api/stalled"))
+            .await
+            .unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(bank.certificate()).unwrap())
+            .timeout(std::time::Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let request = client.get(bank.uri("/api/stalled").to_string()).send();
+        let (response, open) = tokio::join!(request, async {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            bank.open_connections()
+        });
+        assert!(response.unwrap_err().is_timeout());
+        assert_eq!(open, 1);
+
+        drop(client);
+        for _ in 0..200 {
+            if bank.open_connections() == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("connection left open");
+    }
+}
@@ -0,0 +1,128 @@
+//! Deadlines of the phases of a session.
+//!
+//! A stuck MPC setup, an unreachable target and a slow response each expire with their own
+//! [`PhaseTimeout`], so they can be told apart in logs, audit records and webhook events.
+//! The [`Deadline::code`] is also recorded as the `error_code` of the session span, and
+//! labels the `session.timeouts` counter, both exported over OTLP with `telemetry`.
+
+use crate::config::SessionTimeouts;
+use opentelemetry::{global, KeyValue};
+use std::{fmt, future::Future, time::Duration};
+
+/// Phase of a session with its own deadline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deadline {
+    /// MPC setup between prover and verifier
+    Setup,
+    /// TCP connection and MPC-TLS handshake with the target server
+    Connect,
+    /// HTTP request and response over MPC-TLS
+    Response,
+    /// Proving or verifying the revealed transcript, and closing the session
+    Finalize,
+}
+
+impl Deadline {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Setup => "setup",
+            Self::Connect => "connect",
+            Self::Response => "response",
+            Self::Finalize => "finalize",
+        }
+    }
+
+    /// Error code reported when the deadline expires
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Setup => "setup_timeout",
+            Self::Connect => "connect_timeout",
+            Self::Response => "response_timeout",
+            Self::Finalize => "finalize_timeout",
+        }
+    }
+}
+
+/// Counts `deadline` expiring in the `session.timeouts` metric, labelled with its
+/// `error_code`.
+pub(crate) fn count_expired(deadline: Deadline) {
+    global::meter("prover-server")
+        .u64_counter("session.timeouts")
+        .with_description("Sessions that missed a deadline")
+        .build()
+        .add(1, &[KeyValue::new("error_code", deadline.code())]);
+}
+
+impl SessionTimeouts {
+    /// Configured duration of `deadline`.
+    pub fn get(&self, deadline: Deadline) -> Duration {
+        Duration::from_secs(match deadline {
+            Deadline::Setup => self.setup_secs,
+            Deadline::Connect => self.connect_secs,
+            Deadline::Response => self.response_secs,
+            Deadline::Finalize => self.finalize_secs,
+        })
+    }
+
+    /// Deadline of the verifier for the prover's connection to the target and its response,
+    /// which it only sees as one step.
+    pub fn upstream(&self) -> Duration {
+        self.get(Deadline::Connect) + self.get(Deadline::Response)
+    }
+}
+
+/// Expiry of a phase deadline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhaseTimeout {
+    pub deadline: Deadline,
+    pub after: Duration,
+}
+
+impl fmt::Display for PhaseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} timed out after {:?}",
+            self.deadline.as_str(),
+            self.after
+        )
+    }
+}
+
+impl std::error::Error for PhaseTimeout {}
+
+/// Runs `future` for at most `after`, failing with a [`PhaseTimeout`] of `deadline`.
+pub async fn within<F: Future>(
+    deadline: Deadline,
+    after: Duration,
+    future: F,
+) -> Result<F::Output, eyre::ErrReport> {
+    tokio::time::timeout(after, future)
+        .await
+        .map_err(|_| PhaseTimeout { deadline, after }.into())
+}
+
+/// The deadline that made a session fail, if any.
+pub fn expired(err: &eyre::ErrReport) -> Option<Deadline> {
+    err.downcast_ref::<PhaseTimeout>()
+        .map(|timeout| timeout.deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_expired_deadline() {
+        let after = Duration::from_millis(10);
+        let err = within(Deadline::Response, after, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert_eq!(expired(&err), Some(Deadline::Response));
+        assert_eq!(err.to_string(), "response timed out after 10ms");
+
+        let value = within(Deadline::Setup, after, async { 7 }).await.unwrap();
+        assert_eq!(value, 7);
+        assert_eq!(expired(&eyre::eyre!("Verification failed")), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! TLS settings: certificates trusted for the connection to the target server, and
+//! termination of `wss://` connections on the WebSocket listener.
+
+use crate::config::{ListenerTlsConfig, TargetTlsConfig};
+use axum::{
+    extract::Request,
+    http::{uri::Authority, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use eyre::eyre;
+use std::{
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{error, info};
+
+/// Root certificates for the target server, loaded once at startup
+#[derive(Clone, Debug, Default)]
+pub struct TargetRoots {
+    /// DER-encoded root certificates from the configured PEM files
+    pub certs: Vec<Vec<u8>>,
+    /// Whether `certs` replace the Mozilla roots instead of extending them
+    pub custom_only: bool,
+}
+
+impl TargetRoots {
+    pub fn load(config: &TargetTlsConfig) -> Result<Self, eyre::ErrReport> {
+        if config.custom_roots_only && config.root_certs.is_empty() {
+            return Err(eyre!(
+                "custom_roots_only is set but no root_certs are configured"
+            ));
+        }
+
+        let mut certs = Vec::new();
+        for path in &config.root_certs {
+            certs.extend(load_pem_certs(path)?);
+        }
+
+        Ok(Self {
+            certs,
+            custom_only: config.custom_roots_only,
+        })
+    }
+}
+
+/// Reads every certificate of a PEM file as DER.
+pub fn load_pem_certs(path: &Path) -> Result<Vec<Vec<u8>>, eyre::ErrReport> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| eyre!("Failed to open certificate file {}: {err}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map(|cert| cert.map(|cert| cert.to_vec()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| eyre!("Malformed certificate file {}: {err}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(eyre!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// TLS termination for the WebSocket listener, picking up renewed certificates
+pub struct ListenerTls {
+    config: ListenerTlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+    /// Modification times of the certificate and key files currently in use
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ListenerTls {
+    pub fn load(config: &ListenerTlsConfig) -> Result<Self, eyre::ErrReport> {
+        let modified = modification_times(config);
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(config)?));
+        Ok(Self {
+            config: config.clone(),
+            acceptor: RwLock::new(acceptor),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Acceptor for a new connection, using the latest certificate.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Reloads the certificate if its files changed. Returns whether it was replaced.
+    ///
+    /// The current certificate is kept if the new files can't be loaded, for instance
+    /// while only one of them has been written.
+    pub fn reload(&self) -> Result<bool, eyre::ErrReport> {
+        let modified = modification_times(&self.config);
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&self.config)?));
+        *self.acceptor.write().unwrap() = acceptor;
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+
+    /// Checks for a renewed certificate every `reload_interval_secs`.
+    pub fn spawn_reload(self: &Arc<Self>) -> JoinHandle<()> {
+        let tls = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(tls.config.reload_interval_secs.max(1)));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match tls.reload() {
+                    Ok(true) => info!(
+                        "Reloaded TLS certificate from {}",
+                        tls.config.cert_path.display()
+                    ),
+                    Ok(false) => {}
+                    Err(err) => error!("Failed to reload TLS certificate: {err}"),
+                }
+            }
+        })
+    }
+}
+
+fn modification_times(config: &ListenerTlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&config.cert_path), modified(&config.key_path))
+}
+
+fn server_config(config: &ListenerTlsConfig) -> Result<ServerConfig, eyre::ErrReport> {
+    let file = std::fs::File::open(&config.cert_path).map_err(|err| {
+        eyre!(
+            "Failed to open certificate file {}: {err}",
+            config.cert_path.display()
+        )
+    })?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            eyre!(
+                "Malformed certificate file {}: {err}",
+                config.cert_path.display()
+            )
+        })?;
+
+    let file = std::fs::File::open(&config.key_path).map_err(|err| {
+        eyre!(
+            "Failed to open private key file {}: {err}",
+            config.key_path.display()
+        )
+    })?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| {
+            eyre!(
+                "Malformed private key file {}: {err}",
+                config.key_path.display()
+            )
+        })?
+        .ok_or_else(|| eyre!("No private key found in {}", config.key_path.display()))?;
+
+    server_config_with(certs, key)
+}
+
+/// HTTP/1.1 server configuration for a certificate chain and its private key.
+pub(crate) fn server_config_with(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig, eyre::ErrReport> {
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| eyre!("Invalid TLS configuration: {err}"))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| eyre!("Invalid TLS certificate or key: {err}"))?;
+    // The server only speaks HTTP/1.1, which WebSocket upgrades rely on
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+/// Redirects plain HTTP requests on `address` to the HTTPS listener on `https_port`.
+pub async fn run_https_redirect(
+    address: SocketAddr,
+    https_port: u16,
+) -> Result<(), eyre::ErrReport> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|err| eyre!("Failed to bind redirect listener to {address}: {err}"))?;
+    info!("Redirecting HTTP traffic at {address} to HTTPS");
+
+    let router = Router::new().fallback(move |headers: HeaderMap, request: Request| async move {
+        redirect_to_https(&headers, &request, https_port)
+    });
+    axum::serve(listener, router)
+        .await
+        .map_err(|err| eyre!("Redirect listener failed: {err}"))
+}
+
+fn redirect_to_https(headers: &HeaderMap, request: &Request, https_port: u16) -> Response {
+    let host = headers
+        .get(axum::http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    Redirect::permanent(&https_location(host.host(), https_port, path)).into_response()
+}
+
+fn https_location(host: &str, https_port: u16, path: &str) -> String {
+    match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_self_signed(config: &ListenerTlsConfig) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&config.cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&config.key_path, certified.key_pair.serialize_pem()).unwrap();
+    }
+
+    /// Sets the modification time explicitly, since it may only be recorded to the second
+    fn set_modified(path: &Path, secs: u64) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn temp_pem(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("roots-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_configured_roots() {
+        let path = temp_pem(
+            "-----BEGIN CERTIFICATE-----\nAQID\n-----END CERTIFICATE-----\n\
+             -----BEGIN CERTIFICATE-----\nBAUG\n-----END CERTIFICATE-----\n",
+        );
+        let roots = TargetRoots::load(&TargetTlsConfig {
+            root_certs: vec![path.clone()],
+            custom_roots_only: true,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(roots.certs, vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        assert!(roots.custom_only);
+    }
+
+    #[test]
+    fn rejects_missing_roots() {
+        let custom_only = TargetTlsConfig {
+            root_certs: Vec::new(),
+            custom_roots_only: true,
+        };
+        assert!(TargetRoots::load(&custom_only).is_err());
+
+        let path = temp_pem("no certificates here\n");
+        assert!(load_pem_certs(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reloads_renewed_certificate() {
+        let dir = std::env::temp_dir().join(format!("listener-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let config = ListenerTlsConfig {
+            cert_path: dir.join("fullchain.pem"),
+            key_path: dir.join("privkey.pem"),
+            ..ListenerTlsConfig::default()
+        };
+        write_self_signed(&config);
+
+        let tls = ListenerTls::load(&config).unwrap();
+        assert!(!tls.reload().unwrap());
+
+        // A half-written renewal keeps the current certificate until the key is valid too
+        std::fs::write(&config.key_path, "").unwrap();
+        set_modified(&config.key_path, 1_000);
+        assert!(tls.reload().is_err());
+
+        write_self_signed(&config);
+        set_modified(&config.cert_path, 2_000);
+        set_modified(&config.key_path, 2_000);
+        assert!(tls.reload().unwrap());
+        assert!(!tls.reload().unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn builds_https_location() {
+        assert_eq!(
+            https_location("example.com", 443, "/verify?wallet=0xaa"),
+            "https://example.com/verify?wallet=0xaa"
+        );
+        assert_eq!(https_location("[::1]", 9816, "/"), "https://[::1]:9816/");
+    }
+}
@@ -0,0 +1,173 @@
+//! Authenticated transcript data, as output by the verifier.
+//!
+//! The raw bytes are kept together with the ranges the verifier authenticated, so hidden
+//! bytes can be told apart from real `\0` bytes and non-UTF-8 data survives. The JSON form
+//! is [`SerializedTranscript`], the shape used by the client SDK with the ranges added.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::eyre;
+use rangeset::RangeSet;
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Transcript of a TLS connection, authenticated in the given ranges only
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "SerializedTranscript", try_from = "SerializedTranscript")]
+pub struct AuthenticatedTranscript {
+    /// Server name proven by the TLS handshake
+    pub server_name: String,
+    /// Sent data, with unauthenticated bytes set to `\0`
+    pub sent: Vec<u8>,
+    /// Received data, with unauthenticated bytes set to `\0`
+    pub received: Vec<u8>,
+    pub sent_authed: RangeSet<usize>,
+    pub received_authed: RangeSet<usize>,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+/// JSON form of an [`AuthenticatedTranscript`], matching `SerializedTranscript` of the client SDK
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SerializedTranscript {
+    pub server_name: String,
+    /// Base64
+    pub sent: String,
+    /// Base64
+    pub received: String,
+    pub timestamp: u64,
+    pub sent_authed: Vec<Range<usize>>,
+    pub received_authed: Vec<Range<usize>>,
+}
+
+impl AuthenticatedTranscript {
+    /// Unix time in milliseconds, for transcripts verified live.
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    /// Sent data with unauthenticated bytes rendered as `🙈`, for logs.
+    pub fn sent_string(&self) -> String {
+        render(&self.sent, &self.sent_authed)
+    }
+
+    /// Received data with unauthenticated bytes rendered as `🙈`, for logs.
+    pub fn received_string(&self) -> String {
+        render(&self.received, &self.received_authed)
+    }
+}
+
+/// `data` with the bytes outside of `authed` rendered as `🙈`.
+pub(crate) fn render(data: &[u8], authed: &RangeSet<usize>) -> String {
+    let mut rendered = String::new();
+    let mut position = 0;
+    for range in authed.iter_ranges() {
+        rendered.push_str(&"🙈".repeat(range.start - position));
+        rendered.push_str(&String::from_utf8_lossy(&data[range.clone()]));
+        position = range.end;
+    }
+    rendered.push_str(&"🙈".repeat(data.len() - position));
+    rendered
+}
+
+impl From<AuthenticatedTranscript> for SerializedTranscript {
+    fn from(transcript: AuthenticatedTranscript) -> Self {
+        Self {
+            server_name: transcript.server_name,
+            sent: STANDARD.encode(&transcript.sent),
+            received: STANDARD.encode(&transcript.received),
+            timestamp: transcript.timestamp,
+            sent_authed: transcript.sent_authed.iter_ranges().collect(),
+            received_authed: transcript.received_authed.iter_ranges().collect(),
+        }
+    }
+}
+
+impl TryFrom<SerializedTranscript> for AuthenticatedTranscript {
+    type Error = eyre::ErrReport;
+
+    fn try_from(serialized: SerializedTranscript) -> Result<Self, Self::Error> {
+        let decode = |name: &str, data: &str, ranges: Vec<Range<usize>>| {
+            let data = STANDARD
+                .decode(data)
+                .map_err(|err| eyre!("{name} data is not valid base64: {err}"))?;
+            if let Some(range) = ranges
+                .iter()
+                .find(|range| range.start > range.end || range.end > data.len())
+            {
+                return Err(eyre!(
+                    "{name} range {range:?} is out of bounds for {} bytes",
+                    data.len()
+                ));
+            }
+            Ok((data, RangeSet::from(ranges)))
+        };
+        let (sent, sent_authed) = decode("Sent", &serialized.sent, serialized.sent_authed)?;
+        let (received, received_authed) =
+            decode("Received", &serialized.received, serialized.received_authed)?;
+
+        Ok(Self {
+            server_name: serialized.server_name,
+            sent,
+            received,
+            sent_authed,
+            received_authed,
+            timestamp: serialized.timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> AuthenticatedTranscript {
+        AuthenticatedTranscript {
+            server_name: "example.com".into(),
+            sent: b"GET / HTTP/1.1\r\n\0\0\0\0".to_vec(),
+            received: vec![0, 0, 0xff, b'a', 0, 0],
+            sent_authed: RangeSet::from(0..16),
+            received_authed: RangeSet::from(2..5),
+            timestamp: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn serializes_like_the_sdk() {
+        let json = serde_json::to_value(transcript()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "serverName": "example.com",
+                "sent": STANDARD.encode(b"GET / HTTP/1.1\r\n\0\0\0\0"),
+                "received": "AAD/YQAA",
+                "timestamp": 1_700_000_000_000u64,
+                "sentAuthed": [{ "start": 0, "end": 16 }],
+                "receivedAuthed": [{ "start": 2, "end": 5 }],
+            })
+        );
+
+        let parsed: AuthenticatedTranscript = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, transcript());
+    }
+
+    #[test]
+    fn rejects_ranges_out_of_bounds() {
+        let mut serialized = SerializedTranscript::from(transcript());
+        serialized.received_authed = vec![0..1, 2..7];
+        assert!(AuthenticatedTranscript::try_from(serialized).is_err());
+    }
+
+    #[test]
+    fn renders_only_unauthenticated_bytes_as_hidden() {
+        // The authenticated `\0` stays a NUL, non-UTF-8 bytes are replaced
+        assert_eq!(transcript().received_string(), "🙈🙈\u{fffd}a\0🙈");
+        assert_eq!(transcript().sent_string(), "GET / HTTP/1.1\r\n🙈🙈🙈🙈");
+    }
+}
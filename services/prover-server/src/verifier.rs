@@ -1,94 +1,249 @@
-use crate::config::{MAX_RECV_DATA, MAX_SENT_DATA};
+use crate::{
+    audit::transcript_digest,
+    config::{ProtocolLimits, RedactionPolicy, SessionTimeouts},
+    redaction::{check_required_content, check_response_head, extract_claims},
+    telemetry::Phase,
+    timeouts::{within, Deadline},
+    tls::TargetRoots,
+    transcript::AuthenticatedTranscript,
+};
 use eyre::eyre;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use tlsn::{
-    config::ProtocolConfigValidator,
+    config::{CertificateDer, ProtocolConfigValidator, RootCertStore},
     connection::ServerName,
     verifier::{Verifier, VerifierConfig, VerifierOutput, VerifyConfig},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::compat::TokioAsyncReadCompatExt;
-use tracing::{debug, info};
+use tracing::{debug, info, Instrument};
+
+/// Transcript data revealed by the prover and authenticated by the verifier
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifiedData {
+    pub transcript: AuthenticatedTranscript,
+    /// Hex-encoded digest of the authenticated bytes, see [`transcript_digest`]
+    pub transcript_digest: String,
+    /// Named capture groups of the policy's regex rules
+    pub claims: BTreeMap<String, String>,
+}
 
 /// Core verifier logic that validates the TLS proof
 pub async fn verifier<T: AsyncWrite + AsyncRead + Send + Unpin + 'static>(
     socket: T,
     server_domain: &str,
-) -> Result<(String, String), eyre::ErrReport> {
+    roots: &TargetRoots,
+    policy: &RedactionPolicy,
+    limits: &ProtocolLimits,
+    timeouts: &SessionTimeouts,
+) -> Result<VerifiedData, eyre::ErrReport> {
     debug!("Starting verification...");
 
     // Setup Verifier.
-    let config_validator = ProtocolConfigValidator::builder()
-        .max_sent_data(MAX_SENT_DATA)
-        .max_recv_data(MAX_RECV_DATA)
-        .build()
-        .unwrap();
-
-    let verifier_config = VerifierConfig::builder()
-        .protocol_config_validator(config_validator)
-        .build()
-        .unwrap();
-    let verifier = Verifier::new(verifier_config);
+    let verifier = within(
+        Deadline::Setup,
+        timeouts.get(Deadline::Setup),
+        Verifier::new(verifier_config(roots, limits)?)
+            .setup(socket.compat())
+            .instrument(Phase::Setup.span()),
+    )
+    .await?
+    .map_err(|e| eyre!("Verification setup failed: {}", e))?;
 
     // Receive authenticated data.
     debug!("Starting MPC-TLS verification...");
+    let mut verifier = within(
+        Deadline::Response,
+        timeouts.upstream(),
+        verifier.run().instrument(Phase::Request.span()),
+    )
+    .await?
+    .map_err(|e| eyre!("Verification failed: {}", e))?;
 
     let VerifierOutput {
         server_name,
         transcript,
         ..
-    } = verifier
-        .verify(socket.compat(), &VerifyConfig::default())
-        .await
-        .map_err(|e| eyre!("Verification failed: {}", e))?;
+    } = within(
+        Deadline::Finalize,
+        timeouts.get(Deadline::Finalize),
+        async {
+            let prove_span = Phase::Prove.span();
+            let output = verifier
+                .verify(&VerifyConfig::default())
+                .instrument(prove_span.clone())
+                .await
+                .map_err(|e| eyre!("Verification failed: {}", e))?;
+            // `handle_socket` enters the close phase once the session ended, however it ended
+            verifier
+                .close()
+                .instrument(prove_span)
+                .await
+                .map_err(|e| eyre!("Failed to close verification session: {}", e))?;
+            Ok::<_, eyre::ErrReport>(output)
+        },
+    )
+    .await??;
 
     let server_name =
         server_name.ok_or_else(|| eyre!("prover should have revealed server name"))?;
     let transcript =
         transcript.ok_or_else(|| eyre!("prover should have revealed transcript data"))?;
 
+    let ServerName::Dns(dns_name) = server_name;
+    let transcript = AuthenticatedTranscript {
+        server_name: dns_name.as_str().to_string(),
+        sent: transcript.sent_unsafe().to_vec(),
+        received: transcript.received_unsafe().to_vec(),
+        sent_authed: transcript.sent_authed().clone(),
+        received_authed: transcript.received_authed().clone(),
+        timestamp: AuthenticatedTranscript::now(),
+    };
+    Phase::Verify
+        .span()
+        .in_scope(|| verify_transcript(transcript, server_domain, policy))
+}
+
+/// Verification rules applied to authenticated transcript data, whether it was received
+/// live or from a presentation.
+pub fn verify_transcript(
+    transcript: AuthenticatedTranscript,
+    server_domain: &str,
+    policy: &RedactionPolicy,
+) -> Result<VerifiedData, eyre::ErrReport> {
+    let AuthenticatedTranscript {
+        server_name,
+        sent,
+        received,
+        ..
+    } = &transcript;
+
     // Check sent data: check host.
     debug!("Starting sent data verification...");
-    let sent = transcript.sent_unsafe().to_vec();
-    let sent_data = String::from_utf8(sent.clone()).expect("Verifier expected sent data");
-    sent_data
-        .find(server_domain)
-        .ok_or_else(|| eyre!("Verification failed: Expected host {}", server_domain))?;
+    if !contains(sent, server_domain.as_bytes()) {
+        return Err(eyre!(
+            "Verification failed: Expected host {}",
+            server_domain
+        ));
+    }
 
-    // Check received data: check json and version number.
     debug!("Starting received data verification...");
-    let received = transcript.received_unsafe().to_vec();
-    let response = String::from_utf8(received.clone()).expect("Verifier expected received data");
-
-    debug!("Received data: {:?}", response);
-    response
-        .find("Ethereum Foundation")
-        .ok_or_else(|| eyre!("Verification failed: missing data in received data"))?;
+    debug!("Received data: {:?}", transcript.received_string());
 
     // Check Session info: server name.
-    let ServerName::Dns(dns_name) = server_name;
-    if dns_name.as_str() != server_domain {
+    if server_name != server_domain {
         return Err(eyre!(
             "Verification failed: server name mismatches: {} != {}",
-            dns_name,
+            server_name,
             server_domain
         ));
     }
 
-    let sent_string = bytes_to_redacted_string(&sent)?;
-    let received_string = bytes_to_redacted_string(&received)?;
+    check_required_content(policy, received, &transcript.received_authed)
+        .map_err(|err| eyre!("Verification failed: {err}"))?;
+    check_response_head(policy, received, &transcript.received_authed)
+        .map_err(|err| eyre!("Verification failed: {err}"))?;
+    let claims = extract_claims(policy, received, &transcript.received_authed)
+        .map_err(|err| eyre!("Verification failed: {err}"))?;
 
     info!("============================================");
     info!("Verification successful!");
     info!("============================================");
-    info!("Sent data: {:?}", sent_string);
-    info!("Received data: {:?}", received_string);
+    info!("Sent data: {:?}", transcript.sent_string());
+    info!("Received data: {:?}", transcript.received_string());
+
+    Ok(VerifiedData {
+        transcript_digest: transcript_digest(sent, received),
+        transcript,
+        claims,
+    })
+}
+
+/// Verifier configuration shared by live verification and notarization.
+pub(crate) fn verifier_config(
+    roots: &TargetRoots,
+    limits: &ProtocolLimits,
+) -> Result<VerifierConfig, eyre::ErrReport> {
+    let mut validator = ProtocolConfigValidator::builder();
+    validator
+        .max_sent_data(limits.max_sent_data)
+        .max_recv_data(limits.max_recv_data);
+    if let Some(max_sent_records) = limits.max_sent_records {
+        validator.max_sent_records(max_sent_records);
+    }
+    if let Some(max_recv_records_online) = limits.max_recv_records_online {
+        validator.max_recv_records_online(max_recv_records_online);
+    }
+    let config_validator = validator
+        .build()
+        .map_err(|err| eyre!("Invalid protocol config validator: {err}"))?;
+
+    VerifierConfig::builder()
+        .root_store(root_store(roots))
+        .protocol_config_validator(config_validator)
+        .build()
+        .map_err(|err| eyre!("Invalid verifier config: {err}"))
+}
+
+/// Root store checking the server identity, shared with the prover so both sides agree.
+pub(crate) fn root_store(roots: &TargetRoots) -> RootCertStore {
+    let mut root_store = if roots.custom_only {
+        RootCertStore { roots: Vec::new() }
+    } else {
+        RootCertStore::mozilla()
+    };
+    root_store
+        .roots
+        .extend(roots.certs.iter().cloned().map(CertificateDer));
+    root_store
+}
 
-    Ok((sent_string, received_string))
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
 }
 
-/// Render redacted bytes as `🙈`.
-fn bytes_to_redacted_string(bytes: &[u8]) -> Result<String, eyre::ErrReport> {
-    Ok(String::from_utf8(bytes.to_vec())
-        .map_err(|err| eyre!("Failed to parse bytes to redacted string: {err}"))?
-        .replace('\0', "🙈"))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{RegexRule, RegexScope},
+        redaction::{redact, reveal},
+    };
+    use rangeset::RangeSet;
+
+    #[test]
+    fn verifies_targets_without_required_content() {
+        let sent = b"GET /status HTTP/1.1\r\nhost: example.com\r\n\r\n".to_vec();
+        let received =
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/html\r\n\r\n<p>Status: <b>eligible</b></p>"
+                .to_vec();
+        let policy = RedactionPolicy {
+            reveal_json: Vec::new(),
+            reveal_regex: vec![RegexRule {
+                pattern: r"Status: <b>(?<status>\w+)</b>".to_string(),
+                scope: RegexScope::Body,
+            }],
+            required_content: Vec::new(),
+            ..RedactionPolicy::default()
+        };
+        let recv = reveal(&policy, &sent, &received).unwrap().recv;
+        let transcript = AuthenticatedTranscript {
+            server_name: "example.com".to_string(),
+            sent_authed: RangeSet::from(0..sent.len()),
+            sent,
+            received: redact(&received, &recv),
+            received_authed: recv,
+            timestamp: AuthenticatedTranscript::now(),
+        };
+
+        let verified = verify_transcript(transcript.clone(), "example.com", &policy).unwrap();
+        assert_eq!(verified.claims["status"], "eligible");
+
+        let swissbank = RedactionPolicy {
+            required_content: RedactionPolicy::default().required_content,
+            ..policy
+        };
+        assert!(verify_transcript(transcript, "example.com", &swissbank).is_err());
+    }
 }
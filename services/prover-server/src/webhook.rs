@@ -0,0 +1,605 @@
+//! Delivery of session results to configured webhook sinks.
+//!
+//! Every event is signed with HMAC-SHA256 and retried with exponential backoff. Each sink
+//! has its own worker, so a slow sink doesn't hold up the others. Pending deliveries and
+//! the latest `max_dead_letters` dead letters are persisted to a state file, so a restart
+//! resumes where the previous process stopped.
+
+use crate::config::WebhooksConfig;
+use eyre::eyre;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Header carrying `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Header carrying the event ID, identical across retries
+pub const EVENT_ID_HEADER: &str = "X-Webhook-Id";
+
+/// Upper bound on how long the worker sleeps when nothing is pending
+const IDLE_WAIT: Duration = Duration::from_secs(3600);
+
+/// Kind of session that finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    Prove,
+    Verify,
+    Notarize,
+}
+
+/// How a session finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+/// JSON body sent to every sink
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub timestamp: u64,
+    pub session_id: String,
+    pub kind: SessionKind,
+    pub status: SessionStatus,
+    pub wallet: Option<String>,
+    pub target: String,
+    pub transcript_digest: Option<String>,
+    pub error: Option<String>,
+    /// Expired deadline of a timed out session, e.g. `setup_timeout`
+    pub error_code: Option<String>,
+}
+
+impl WebhookEvent {
+    pub fn new(
+        session_id: String,
+        kind: SessionKind,
+        status: SessionStatus,
+        target: String,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            timestamp: now_millis() / 1000,
+            session_id,
+            kind,
+            status,
+            wallet: None,
+            target,
+            transcript_digest: None,
+            error: None,
+            error_code: None,
+        }
+    }
+}
+
+/// An event on its way to one sink
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    /// Index of the sink in the configuration, resolved again from `url` on restore
+    #[serde(skip)]
+    pub sink: usize,
+    pub event: WebhookEvent,
+    pub attempts: u32,
+    /// Unix time in milliseconds of the next attempt
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WebhookState {
+    pending: Vec<Delivery>,
+    dead_letters: Vec<Delivery>,
+}
+
+/// Queues events and delivers them in a background task.
+#[derive(Debug)]
+pub struct WebhookDispatcher {
+    config: WebhooksConfig,
+    client: reqwest::Client,
+    state: Mutex<WebhookState>,
+    /// Wakes the worker of the sink with the same index
+    wake: Vec<Notify>,
+}
+
+impl WebhookDispatcher {
+    /// Creates a dispatcher, restoring pending deliveries from the state file if there is one.
+    pub fn new(config: WebhooksConfig) -> Result<Self, eyre::ErrReport> {
+        let mut state: WebhookState = match &config.state_path {
+            Some(path) => match std::fs::read(path) {
+                Ok(contents) => serde_json::from_slice(&contents).map_err(|err| {
+                    eyre!("Failed to parse webhook state {}: {err}", path.display())
+                })?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => WebhookState::default(),
+                Err(err) => {
+                    return Err(eyre!(
+                        "Failed to read webhook state {}: {err}",
+                        path.display()
+                    ))
+                }
+            },
+            None => WebhookState::default(),
+        };
+        // Deliveries to sinks removed from the configuration have no worker left
+        for mut delivery in std::mem::take(&mut state.pending) {
+            match config
+                .sinks
+                .iter()
+                .position(|sink| sink.url == delivery.url)
+            {
+                Some(sink) => {
+                    delivery.sink = sink;
+                    state.pending.push(delivery);
+                }
+                None => {
+                    delivery.last_error = Some("sink is no longer configured".into());
+                    state.dead_letters.push(delivery);
+                }
+            }
+        }
+        truncate_dead_letters(&mut state, config.max_dead_letters);
+        if !state.pending.is_empty() {
+            info!(
+                "Resuming {} pending webhook deliveries",
+                state.pending.len()
+            );
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            wake: config.sinks.iter().map(|_| Notify::new()).collect(),
+            config,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Starts a delivery worker per sink. Aborting the returned task stops all of them.
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            let mut workers = JoinSet::new();
+            for sink in 0..dispatcher.config.sinks.len() {
+                let dispatcher = dispatcher.clone();
+                workers.spawn(async move { dispatcher.run(sink).await });
+            }
+            while workers.join_next().await.is_some() {}
+        })
+    }
+
+    /// Queues `event` for every configured sink.
+    pub async fn enqueue(&self, event: WebhookEvent) {
+        let now = now_millis();
+        let mut state = self.state.lock().await;
+        for (index, sink) in self.config.sinks.iter().enumerate() {
+            state.pending.push(Delivery {
+                id: Uuid::new_v4().to_string(),
+                url: sink.url.clone(),
+                sink: index,
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: now,
+                last_error: None,
+            });
+        }
+        self.persist(&state).await;
+        drop(state);
+
+        for wake in &self.wake {
+            wake.notify_one();
+        }
+    }
+
+    /// Deliveries that ran out of attempts, oldest first.
+    pub async fn dead_letters(&self) -> Vec<Delivery> {
+        self.state.lock().await.dead_letters.clone()
+    }
+
+    /// Delivers the events of the `sink`-th sink, in order.
+    async fn run(&self, sink: usize) {
+        loop {
+            let due: Vec<Delivery> = {
+                let now = now_millis();
+                let state = self.state.lock().await;
+                state
+                    .pending
+                    .iter()
+                    .filter(|delivery| delivery.sink == sink && delivery.next_attempt_at <= now)
+                    .cloned()
+                    .collect()
+            };
+
+            for delivery in due {
+                let result = self.deliver(&delivery).await;
+                self.record_attempt(&delivery.id, result).await;
+            }
+
+            let wait = {
+                let state = self.state.lock().await;
+                state
+                    .pending
+                    .iter()
+                    .filter(|delivery| delivery.sink == sink)
+                    .map(|delivery| delivery.next_attempt_at)
+                    .min()
+                    .map(|next| Duration::from_millis(next.saturating_sub(now_millis())))
+                    .unwrap_or(IDLE_WAIT)
+            };
+            tokio::select! {
+                _ = self.wake[sink].notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    async fn deliver(&self, delivery: &Delivery) -> Result<(), eyre::ErrReport> {
+        let sink = &self.config.sinks[delivery.sink];
+        let body = serde_json::to_vec(&delivery.event)?;
+        let timestamp = now_millis() / 1000;
+
+        let response = self
+            .client
+            .post(&sink.url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_ID_HEADER, &delivery.event.id)
+            .header(SIGNATURE_HEADER, sign(&sink.secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(eyre!("sink responded with {}", response.status()));
+        }
+        Ok(())
+    }
+
+    async fn record_attempt(&self, id: &str, result: Result<(), eyre::ErrReport>) {
+        let mut state = self.state.lock().await;
+        let Some(index) = state.pending.iter().position(|delivery| delivery.id == id) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                let delivery = state.pending.remove(index);
+                debug!(
+                    "Delivered webhook event {} to {}",
+                    delivery.event.id, delivery.url
+                );
+            }
+            Err(err) => {
+                let delivery = &mut state.pending[index];
+                delivery.attempts += 1;
+                delivery.last_error = Some(err.to_string());
+
+                if delivery.attempts >= self.config.max_attempts {
+                    let delivery = state.pending.remove(index);
+                    error!(
+                        "Giving up on webhook event {} to {} after {} attempts: {err}",
+                        delivery.event.id, delivery.url, delivery.attempts
+                    );
+                    state.dead_letters.push(delivery);
+                    truncate_dead_letters(&mut state, self.config.max_dead_letters);
+                } else {
+                    let backoff = self.backoff(delivery.attempts);
+                    delivery.next_attempt_at = now_millis() + backoff.as_millis() as u64;
+                    warn!(
+                        "Webhook event {} to {} failed, retrying in {:?}: {err}",
+                        delivery.event.id, delivery.url, backoff
+                    );
+                }
+            }
+        }
+        self.persist(&state).await;
+    }
+
+    /// Delay before the attempt following the `attempts`-th failure.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        Duration::from_millis(
+            self.config
+                .retry_base_ms
+                .saturating_mul(factor)
+                .min(self.config.retry_max_ms),
+        )
+    }
+
+    async fn persist(&self, state: &WebhookState) {
+        let Some(path) = &self.config.state_path else {
+            return;
+        };
+        if let Err(err) = write_atomically(path, state).await {
+            error!(
+                "Failed to persist webhook state to {}: {err}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Drops the oldest dead letters beyond `max`.
+fn truncate_dead_letters(state: &mut WebhookState, max: usize) {
+    let excess = state.dead_letters.len().saturating_sub(max);
+    state.dead_letters.drain(..excess);
+}
+
+async fn write_atomically(path: &PathBuf, state: &WebhookState) -> Result<(), eyre::ErrReport> {
+    let contents = serde_json::to_vec_pretty(state)?;
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Value of the [`SIGNATURE_HEADER`] for `body` sent at `timestamp`.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!(
+        "t={timestamp},v1={}",
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Unix time in milliseconds.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebhookSink;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, routing::post, Router};
+    use std::sync::Mutex as StdMutex;
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        /// Number of requests to reject before accepting
+        failures: Arc<StdMutex<u32>>,
+        received: Arc<StdMutex<Vec<(String, Bytes)>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> http::StatusCode {
+        let mut failures = receiver.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return http::StatusCode::SERVICE_UNAVAILABLE;
+        }
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+        receiver.received.lock().unwrap().push((signature, body));
+        http::StatusCode::NO_CONTENT
+    }
+
+    async fn start_receiver(receiver: Receiver) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    fn config(url: String, max_attempts: u32, state_path: Option<PathBuf>) -> WebhooksConfig {
+        WebhooksConfig {
+            sinks: vec![WebhookSink {
+                url,
+                secret: "shh".into(),
+            }],
+            state_path,
+            max_attempts,
+            retry_base_ms: 10,
+            retry_max_ms: 50,
+            max_dead_letters: 100,
+        }
+    }
+
+    fn event() -> WebhookEvent {
+        WebhookEvent::new(
+            "session".into(),
+            SessionKind::Verify,
+            SessionStatus::Succeeded,
+            "swissbank.tlsnotary.org".into(),
+        )
+    }
+
+    async fn wait_for(mut condition: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn retries_and_signs_deliveries() {
+        let receiver = Receiver::default();
+        *receiver.failures.lock().unwrap() = 2;
+        let url = start_receiver(receiver.clone()).await;
+
+        let dispatcher = Arc::new(WebhookDispatcher::new(config(url, 5, None)).unwrap());
+        dispatcher.spawn();
+        let event = event();
+        dispatcher.enqueue(event.clone()).await;
+
+        wait_for(|| !receiver.received.lock().unwrap().is_empty()).await;
+        let (signature, body) = receiver.received.lock().unwrap()[0].clone();
+        let timestamp: u64 = signature
+            .strip_prefix("t=")
+            .and_then(|rest| rest.split(',').next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(signature, sign("shh", timestamp, &body));
+        assert_eq!(
+            serde_json::from_slice::<WebhookEvent>(&body).unwrap(),
+            event
+        );
+        assert!(dispatcher.dead_letters().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dead_letters_survive_restart() {
+        let receiver = Receiver::default();
+        *receiver.failures.lock().unwrap() = u32::MAX;
+        let url = start_receiver(receiver).await;
+        let state_path = std::env::temp_dir().join(format!("webhooks-{}.json", Uuid::new_v4()));
+
+        let dispatcher = Arc::new(
+            WebhookDispatcher::new(config(url.clone(), 3, Some(state_path.clone()))).unwrap(),
+        );
+        let worker = dispatcher.spawn();
+        dispatcher.enqueue(event()).await;
+
+        for _ in 0..200 {
+            if !dispatcher.dead_letters().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        worker.abort();
+
+        let restored = WebhookDispatcher::new(config(url, 3, Some(state_path.clone()))).unwrap();
+        let dead_letters = restored.dead_letters().await;
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 3);
+        assert!(dead_letters[0].last_error.is_some());
+
+        std::fs::remove_file(state_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_sinks_dont_hold_up_others() {
+        let receiver = Receiver::default();
+        let fast_url = start_receiver(receiver.clone()).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let slow = Router::new().route(
+            "/hook",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                http::StatusCode::NO_CONTENT
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, slow).await.unwrap() });
+
+        let mut config = config(slow_url, 5, None);
+        config.sinks.push(WebhookSink {
+            url: fast_url,
+            secret: "shh".into(),
+        });
+        let dispatcher = Arc::new(WebhookDispatcher::new(config).unwrap());
+        dispatcher.spawn();
+        dispatcher.enqueue(event()).await;
+        dispatcher.enqueue(event()).await;
+
+        // Well within the 10s the slow sink blocks its own worker
+        wait_for(|| receiver.received.lock().unwrap().len() == 2).await;
+    }
+
+    #[tokio::test]
+    async fn signs_with_the_secret_of_each_sink() {
+        let receiver = Receiver::default();
+        let url = start_receiver(receiver.clone()).await;
+        let mut config = config(url.clone(), 5, None);
+        config.sinks.push(WebhookSink {
+            url,
+            secret: "psst".into(),
+        });
+
+        let dispatcher = Arc::new(WebhookDispatcher::new(config).unwrap());
+        dispatcher.spawn();
+        dispatcher.enqueue(event()).await;
+
+        wait_for(|| receiver.received.lock().unwrap().len() == 2).await;
+        let mut secrets: Vec<_> = receiver
+            .received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(signature, body)| {
+                let timestamp: u64 = signature
+                    .strip_prefix("t=")
+                    .and_then(|rest| rest.split(',').next())
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                ["shh", "psst"]
+                    .into_iter()
+                    .find(|secret| *signature == sign(secret, timestamp, body))
+                    .unwrap()
+            })
+            .collect();
+        secrets.sort();
+        assert_eq!(secrets, ["psst", "shh"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_only_the_latest_dead_letters() {
+        let receiver = Receiver::default();
+        *receiver.failures.lock().unwrap() = u32::MAX;
+        let url = start_receiver(receiver).await;
+        let mut config = config(url, 1, None);
+        config.max_dead_letters = 2;
+
+        let dispatcher = Arc::new(WebhookDispatcher::new(config).unwrap());
+        dispatcher.spawn();
+        let events: Vec<_> = (0..3).map(|_| event()).collect();
+        for event in &events {
+            dispatcher.enqueue(event.clone()).await;
+        }
+
+        for _ in 0..200 {
+            if dispatcher.state.lock().await.pending.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dead_letters: Vec<_> = dispatcher
+            .dead_letters()
+            .await
+            .into_iter()
+            .map(|delivery| delivery.event.id)
+            .collect();
+        assert_eq!(dead_letters, [events[1].id.clone(), events[2].id.clone()]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let dispatcher = WebhookDispatcher::new(config("http://unused".into(), 10, None)).unwrap();
+        let delays: Vec<_> = (1..=5)
+            .map(|attempts| dispatcher.backoff(attempts).as_millis())
+            .collect();
+        assert_eq!(delays, [10, 20, 40, 50, 50]);
+    }
+}
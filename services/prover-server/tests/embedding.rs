@@ -0,0 +1,50 @@
+//! Mounts the server endpoints in another axum app, as a library user would.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+use server::{config::Config, ServerBuilder};
+use tower_service::Service;
+
+#[derive(Clone)]
+struct AppState {
+    name: &'static str,
+}
+
+async fn get_body(router: &mut Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = router.call(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn merges_into_an_app_with_its_own_state() {
+    let server = ServerBuilder::new(Config::default())
+        .build::<AppState>()
+        .unwrap();
+    assert!(server.admin_router.is_none());
+    assert!(server.tasks.is_empty());
+
+    let mut app = Router::new()
+        .route(
+            "/name",
+            get(|State(state): State<AppState>| async move { state.name }),
+        )
+        .merge(server.router)
+        .with_state(AppState { name: "bank" });
+
+    assert_eq!(
+        get_body(&mut app, "/name").await,
+        (StatusCode::OK, "bank".to_string())
+    );
+    assert_eq!(
+        get_body(&mut app, "/notarize/key").await,
+        (StatusCode::NOT_FOUND, "Notarization is not enabled".to_string())
+    );
+}
@@ -0,0 +1,143 @@
+//! Runs the prover and verifier against each other and against a local mock bank,
+//! connected through an in-memory duplex instead of a WebSocket, or through a served
+//! `/prove` endpoint to test sessions end to end.
+
+use server::{
+    config::{Config, ProtocolLimits, ProtocolTuning, RedactionPolicy, SessionTimeouts},
+    prover::prover,
+    telemetry::Phase,
+    test_support::{Fixtures, MockBank},
+    timeouts::{expired, Deadline},
+    tls::TargetRoots,
+    verifier::verifier,
+    ServerBuilder,
+};
+use std::time::Duration;
+use tokio::{net::TcpListener, runtime::Handle};
+use ws_stream_tungstenite::WsStream;
+
+/// Polls `condition` for up to a minute, MPC-TLS being slow in debug builds.
+async fn wait_for(mut condition: impl FnMut() -> bool) {
+    for _ in 0..6000 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proves_balances_to_verifier() {
+    let bank = MockBank::start(Fixtures::default()).await.unwrap();
+    let uri = bank.uri("/api/balances");
+    let roots = bank.roots();
+    let policy = RedactionPolicy::default();
+    let (tuning, limits) = (ProtocolTuning::default(), ProtocolLimits::default());
+    let timeouts = SessionTimeouts::default();
+    let (prover_socket, verifier_socket) = tokio::io::duplex(1 << 16);
+
+    let (proved, verified) = tokio::join!(
+        prover(prover_socket, &uri, &roots, &policy, &tuning, &timeouts),
+        verifier(
+            verifier_socket,
+            "localhost",
+            &roots,
+            &policy,
+            &limits,
+            &timeouts
+        ),
+    );
+    proved.unwrap();
+    let verified = verified.unwrap();
+
+    let transcript = verified.transcript;
+    assert_eq!(transcript.server_name, "localhost");
+    let received = transcript.received_string();
+    assert!(received.contains("\"organization\": \"Ethereum Foundation\""));
+    assert!(received.contains("\"USD\": \"1000\""));
+    // The authorization token stays hidden from the verifier
+    assert!(!transcript.sent_string().contains("random_auth_token"));
+    assert!(transcript.sent_authed.len() < transcript.sent.len());
+}
+
+#[tokio::test]
+async fn times_out_waiting_for_setup() {
+    let timeouts = SessionTimeouts {
+        setup_secs: 1,
+        ..SessionTimeouts::default()
+    };
+    // The prover never starts the MPC setup
+    let (_prover_socket, verifier_socket) = tokio::io::duplex(1 << 16);
+
+    let err = verifier(
+        verifier_socket,
+        "localhost",
+        &TargetRoots::default(),
+        &RedactionPolicy::default(),
+        &ProtocolLimits::default(),
+        &timeouts,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(expired(&err), Some(Deadline::Setup));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelling_a_session_stops_its_prover() {
+    let bank = MockBank::start(Fixtures::default().stalled("/api/stalled"))
+        .await
+        .unwrap();
+    let roots_path = std::env::temp_dir().join(format!("bank-{}.pem", uuid::Uuid::new_v4()));
+    std::fs::write(&roots_path, bank.certificate_pem()).unwrap();
+    let mut config = Config {
+        server_uri: bank.uri("/api/stalled"),
+        ..Config::default()
+    };
+    config.target_tls.root_certs = vec![roots_path.clone()];
+    config.target_tls.custom_roots_only = true;
+
+    let server = ServerBuilder::new(config).build::<()>().unwrap();
+    let sessions = server.sessions.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = server.router;
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    let metrics = Handle::current().metrics();
+    let baseline = metrics.num_alive_tasks();
+
+    // Verifies for the server's prover, whose request to the bank never gets a response
+    let (ws, _) = async_tungstenite::tokio::connect_async(format!("ws://{address}/prove"))
+        .await
+        .unwrap();
+    let roots = bank.roots();
+    let client = tokio::spawn(async move {
+        verifier(
+            WsStream::new(ws),
+            "localhost",
+            &roots,
+            &RedactionPolicy::default(),
+            &ProtocolLimits::default(),
+            &SessionTimeouts::default(),
+        )
+        .await
+    });
+    wait_for(|| {
+        sessions
+            .list()
+            .first()
+            .is_some_and(|session| session.phase == Some(Phase::Request))
+    })
+    .await;
+    assert_eq!(bank.open_connections(), 1);
+
+    assert!(sessions.cancel(sessions.list()[0].id));
+    wait_for(|| sessions.list().is_empty()).await;
+    // The prover task owned the connection to the bank
+    wait_for(|| bank.open_connections() == 0).await;
+    assert!(client.await.unwrap().is_err());
+    // Nor is the HTTP connection task left running
+    wait_for(|| metrics.num_alive_tasks() == baseline).await;
+
+    std::fs::remove_file(roots_path).unwrap();
+}